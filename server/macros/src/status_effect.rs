@@ -1,6 +1,27 @@
-use proc_macro2::{Span, TokenStream};
-use quote::quote;
-use syn::{Data, DeriveInput, Error, Fields, Meta};
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use std::collections::HashMap;
+use syn::{Data, DeriveInput, Error, Fields, Ident, Index, LitInt, Member, Meta, Variant};
+
+const KINDS: [&str; 4] = ["buff", "debuff", "passive", "curse"];
+
+/// Arguments of the kind attribute, e.g. `#[debuff(id = 2, duration_ms = 5000, dispellable)]`.
+struct EffectAttributes {
+    kind: TokenStream,
+    id: u16,
+    duration_ms: Option<u64>,
+    max_stacks: usize,
+    dispellable: bool,
+    tick_ms: Option<u64>,
+    play_time: bool,
+}
+
+/// A field marked with `#[increases(Stat)]` or `#[decreases(Stat)]`.
+struct Modifier {
+    field: TokenStream,
+    stat: Ident,
+    negative: bool,
+}
 
 pub fn expand_derive_status_effect(input: DeriveInput) -> Result<TokenStream, Error> {
     let data = match input.data {
@@ -12,37 +33,72 @@ pub fn expand_derive_status_effect(input: DeriveInput) -> Result<TokenStream, Er
     };
     let name = input.ident;
 
+    let mut ids = HashMap::new();
     let mut kind_arms = Vec::new();
+    let mut id_arms = Vec::new();
+    let mut duration_arms = Vec::new();
+    let mut max_stacks_arms = Vec::new();
+    let mut dispellable_arms = Vec::new();
+    let mut tick_arms = Vec::new();
+    let mut modifier_arms = Vec::new();
+    let mut play_time_arms = Vec::new();
+    let mut values_arms = Vec::new();
+    let mut from_values_arms = Vec::new();
     for variant in &data.variants {
         let variant_name = &variant.ident;
+        let attributes = parse_effect_attributes(variant)?;
 
-        let mut kind = None;
-        for attr in &variant.attrs {
-            match &attr.meta {
-                Meta::Path(path) if path.is_ident("buff") =>
-                    kind = Some(quote! { StatusEffectKind::Buff }),
-                Meta::Path(path) if path.is_ident("debuff") =>
-                    kind = Some(quote! { StatusEffectKind::Debuff }),
-                Meta::Path(path) if path.is_ident("passive") =>
-                    kind = Some(quote! { StatusEffectKind::Passive }),
-                Meta::Path(path) if path.is_ident("curse") =>
-                    kind = Some(quote! { StatusEffectKind::Curse }),
-                _ => {},
-            }
+        if let Some(other) = ids.insert(attributes.id, variant_name) {
+            return Err(Error::new_spanned(
+                variant_name,
+                format!("StatusEffect id {} is already used by {}", attributes.id, other)
+            ));
         }
 
-        let kind = kind.ok_or_else(|| Error::new_spanned(
-            variant_name,
-            "StatusEffect variant must have a kind attribute (buff, debuff, passive, curse)"
-        ))?;
-
-        match &variant.fields {
-            Fields::Unit => {
-                kind_arms.push(quote! { Self::#variant_name => #kind, })
-            },
-            Fields::Named(_) | Fields::Unnamed(_) => {
-                kind_arms.push(quote! { Self::#variant_name { .. } => #kind, })
-            },
+        let pattern = match &variant.fields {
+            Fields::Unit => quote! { Self::#variant_name },
+            Fields::Named(_) | Fields::Unnamed(_) => quote! { Self::#variant_name { .. } },
+        };
+
+        let EffectAttributes { kind, id, duration_ms, max_stacks, dispellable, tick_ms, play_time } = attributes;
+        let duration = to_duration(duration_ms);
+        let tick = to_duration(tick_ms);
+        kind_arms.push(quote! { #pattern => #kind, });
+        id_arms.push(quote! { #pattern => #id, });
+        duration_arms.push(quote! { #pattern => #duration, });
+        max_stacks_arms.push(quote! { #pattern => #max_stacks, });
+        dispellable_arms.push(quote! { #pattern => #dispellable, });
+        tick_arms.push(quote! { #pattern => #tick, });
+        play_time_arms.push(quote! { #pattern => #play_time, });
+
+        // Fields are bound by their member, so that named and unnamed fields are handled alike
+        let members: Vec<Member> = variant.fields.iter()
+            .enumerate()
+            .map(|(index, field)| match &field.ident {
+                Some(ident) => Member::Named(ident.clone()),
+                None => Member::Unnamed(Index::from(index)),
+            })
+            .collect();
+        let bindings: Vec<Ident> = (0..members.len())
+            .map(|index| format_ident!("value_{}", index))
+            .collect();
+        values_arms.push(quote! {
+            Self::#variant_name { #(#members: #bindings),* } =>
+                ::std::vec![#(*#bindings as i64),*],
+        });
+        from_values_arms.push(quote! {
+            #id => ::std::option::Option::Some(Self::#variant_name { #(#members: ::std::convert::TryInto::try_into(values.next()?).ok()?),* }),
+        });
+
+        match parse_modifier(variant)? {
+            Some(Modifier { field, stat, negative }) => {
+                let value = if negative { quote! { -(*value as f32) } } else { quote! { *value as f32 } };
+                modifier_arms.push(quote! {
+                    Self::#variant_name { #field: value, .. } =>
+                        ::std::option::Option::Some((ModifiedStat::#stat, #value)),
+                });
+            }
+            None => modifier_arms.push(quote! { #pattern => ::std::option::Option::None, }),
         }
     }
 
@@ -53,8 +109,175 @@ pub fn expand_derive_status_effect(input: DeriveInput) -> Result<TokenStream, Er
                     #(#kind_arms)*
                 }
             }
+
+            /// Stable id of the variant, for the network and the database.
+            pub fn id(&self) -> u16 {
+                match self {
+                    #(#id_arms)*
+                }
+            }
+
+            pub fn default_duration(&self) -> ::std::option::Option<::std::time::Duration> {
+                match self {
+                    #(#duration_arms)*
+                }
+            }
+
+            pub fn max_stacks(&self) -> usize {
+                match self {
+                    #(#max_stacks_arms)*
+                }
+            }
+
+            pub fn is_dispellable(&self) -> bool {
+                match self {
+                    #(#dispellable_arms)*
+                }
+            }
+
+            pub fn tick_interval(&self) -> ::std::option::Option<::std::time::Duration> {
+                match self {
+                    #(#tick_arms)*
+                }
+            }
+
+            /// Whether the remaining duration only elapses while the character is in the world.
+            pub fn expires_in_play_time(&self) -> bool {
+                match self {
+                    #(#play_time_arms)*
+                }
+            }
+
+            /// Values of the fields in declaration order, for the network and the database.
+            pub fn values(&self) -> ::std::vec::Vec<i64> {
+                match self {
+                    #(#values_arms)*
+                }
+            }
+
+            pub fn from_values(id: u16, values: &[i64]) -> ::std::option::Option<Self> {
+                let mut values = values.iter().copied();
+                match id {
+                    #(#from_values_arms)*
+                    _ => ::std::option::Option::None,
+                }
+            }
+
+            /// The modified stat and the signed value of the modifying field.
+            pub fn modifier(&self) -> ::std::option::Option<(ModifiedStat, f32)> {
+                match self {
+                    #(#modifier_arms)*
+                }
+            }
         }
     };
 
-    Ok(out.into())
+    Ok(out)
+}
+
+fn parse_effect_attributes(variant: &Variant) -> Result<EffectAttributes, Error> {
+    let mut parsed = None;
+    for attr in &variant.attrs {
+        let Some(kind) = KINDS.iter().find(|kind| attr.path().is_ident(kind)) else {
+            continue;
+        };
+        if parsed.is_some() {
+            return Err(Error::new_spanned(attr, "StatusEffect variant must have only one kind attribute"));
+        }
+
+        let kind = match *kind {
+            "buff" => quote! { StatusEffectKind::Buff },
+            "debuff" => quote! { StatusEffectKind::Debuff },
+            "passive" => quote! { StatusEffectKind::Passive },
+            _ => quote! { StatusEffectKind::Curse },
+        };
+
+        let mut id = None;
+        let mut attributes = EffectAttributes {
+            kind,
+            id: 0,
+            duration_ms: None,
+            max_stacks: 1,
+            dispellable: false,
+            tick_ms: None,
+            play_time: false,
+        };
+
+        if let Meta::List(_) = attr.meta {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("id") {
+                    id = Some(meta.value()?.parse::<LitInt>()?.base10_parse()?);
+                } else if meta.path.is_ident("duration_ms") {
+                    attributes.duration_ms = Some(meta.value()?.parse::<LitInt>()?.base10_parse()?);
+                } else if meta.path.is_ident("max_stacks") {
+                    let lit = meta.value()?.parse::<LitInt>()?;
+                    attributes.max_stacks = lit.base10_parse()?;
+                    if attributes.max_stacks == 0 {
+                        return Err(Error::new_spanned(lit, "max_stacks must be at least 1"));
+                    }
+                } else if meta.path.is_ident("dispellable") {
+                    attributes.dispellable = true;
+                } else if meta.path.is_ident("play_time") {
+                    attributes.play_time = true;
+                } else if meta.path.is_ident("tick_ms") {
+                    let lit = meta.value()?.parse::<LitInt>()?;
+                    let tick_ms = lit.base10_parse()?;
+                    if tick_ms == 0 {
+                        return Err(Error::new_spanned(lit, "tick_ms must be at least 1"));
+                    }
+                    attributes.tick_ms = Some(tick_ms);
+                } else {
+                    return Err(meta.error(
+                        "unknown StatusEffect argument, expected one of id, duration_ms, max_stacks, dispellable, tick_ms, play_time"
+                    ));
+                }
+                Ok(())
+            })?;
+        }
+
+        attributes.id = id.ok_or_else(|| Error::new_spanned(attr, "StatusEffect variant must have an id argument"))?;
+        parsed = Some(attributes);
+    }
+
+    parsed.ok_or_else(|| Error::new_spanned(
+        &variant.ident,
+        "StatusEffect variant must have a kind attribute (buff, debuff, passive, curse)"
+    ))
+}
+
+fn parse_modifier(variant: &Variant) -> Result<Option<Modifier>, Error> {
+    let mut modifier = None;
+    for (index, field) in variant.fields.iter().enumerate() {
+        for attr in &field.attrs {
+            let negative = if attr.path().is_ident("increases") {
+                false
+            } else if attr.path().is_ident("decreases") {
+                true
+            } else {
+                continue;
+            };
+            if modifier.is_some() {
+                return Err(Error::new_spanned(attr, "StatusEffect variant can modify only one stat"));
+            }
+
+            let stat: Ident = attr.parse_args()?;
+            let field = match &field.ident {
+                Some(ident) => quote! { #ident },
+                None => {
+                    let index = Index::from(index);
+                    quote! { #index }
+                }
+            };
+            modifier = Some(Modifier { field, stat, negative });
+        }
+    }
+
+    Ok(modifier)
+}
+
+fn to_duration(ms: Option<u64>) -> TokenStream {
+    match ms {
+        Some(ms) => quote! { ::std::option::Option::Some(::std::time::Duration::from_millis(#ms)) },
+        None => quote! { ::std::option::Option::None },
+    }
 }
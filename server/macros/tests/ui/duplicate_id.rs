@@ -0,0 +1,9 @@
+use macros::StatusEffect;
+
+#[derive(StatusEffect)]
+enum StatusEffect {
+    #[debuff(id = 1)] Stun,
+    #[debuff(id = 1)] Slow { modifier: u8 },
+}
+
+fn main() {}
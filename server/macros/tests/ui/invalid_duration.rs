@@ -0,0 +1,8 @@
+use macros::StatusEffect;
+
+#[derive(StatusEffect)]
+enum StatusEffect {
+    #[debuff(id = 1, duration_ms = "2s")] Stun,
+}
+
+fn main() {}
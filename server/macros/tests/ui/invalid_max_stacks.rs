@@ -0,0 +1,8 @@
+use macros::StatusEffect;
+
+#[derive(StatusEffect)]
+enum StatusEffect {
+    #[buff(id = 1, max_stacks = 0)] Haste { modifier: u8 },
+}
+
+fn main() {}
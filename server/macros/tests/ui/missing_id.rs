@@ -0,0 +1,8 @@
+use macros::StatusEffect;
+
+#[derive(StatusEffect)]
+enum StatusEffect {
+    #[debuff(duration_ms = 2000)] Stun,
+}
+
+fn main() {}
@@ -0,0 +1,8 @@
+use macros::StatusEffect;
+
+#[derive(StatusEffect)]
+enum StatusEffect {
+    Stun,
+}
+
+fn main() {}
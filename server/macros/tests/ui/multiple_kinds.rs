@@ -0,0 +1,10 @@
+use macros::StatusEffect;
+
+#[derive(StatusEffect)]
+enum StatusEffect {
+    #[buff(id = 1)]
+    #[debuff(id = 2)]
+    Stun,
+}
+
+fn main() {}
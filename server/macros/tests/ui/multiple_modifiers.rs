@@ -0,0 +1,11 @@
+use macros::StatusEffect;
+
+#[derive(StatusEffect)]
+enum StatusEffect {
+    #[buff(id = 1)] Haste {
+        #[increases(Speed)] speed: u8,
+        #[increases(Attack)] attack: u8,
+    },
+}
+
+fn main() {}
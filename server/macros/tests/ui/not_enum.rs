@@ -0,0 +1,8 @@
+use macros::StatusEffect;
+
+#[derive(StatusEffect)]
+struct StatusEffect {
+    modifier: u8,
+}
+
+fn main() {}
@@ -0,0 +1,8 @@
+use macros::StatusEffect;
+
+#[derive(StatusEffect)]
+enum StatusEffect {
+    #[debuff(id = 1, duration = 2000)] Stun,
+}
+
+fn main() {}
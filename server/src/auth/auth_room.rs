@@ -1,15 +1,17 @@
+use bevy_ecs::prelude::*;
 use crate::core::config::AuthConfig;
-use crate::core::room::{handle_room_message, RoomContext};
+use crate::core::room::*;
+use crate::core::room_resource::SessionRegistry;
 use crate::core::server::{ServerContext, ServerMessage};
-use crate::core::session::{InMessage, SessionContext};
+use crate::core::session::{CloseReason, InMessage, SessionContext};
 use crate::player::account::*;
 use crate::protocol::*;
 use crate::protocol::auth::{*, auth_client_protocol::Protocol};
-use jsonwebtoken::{Algorithm, Validation, DecodingKey, decode};
+use jsonwebtoken::{Algorithm, Validation, decode};
 use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 use std::sync::Arc;
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::broadcast;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Claims {
@@ -20,83 +22,61 @@ struct Claims {
 
 pub fn run(
     server_ctx: Arc<ServerContext>,
-    mut shutdown_rx: broadcast::Receiver<()>,
+    shutdown_rx: broadcast::Receiver<()>,
 ) -> Arc<RoomContext> {
-    let (room_message_tx, mut room_message_rx) = mpsc::channel(16);
-    let (in_message_tx, mut in_message_rx) = mpsc::channel(64);
+    let builder = RoomBuilder::default()
+        .add_in_message_handler(handle_in_message)
+        .insert_resource(AuthConfig::load());
 
-    let ctx = Arc::new(RoomContext::new(room_message_tx, in_message_tx));
-    let ctx_handle = ctx.clone();
-
-    tokio::spawn(async move {
-        let auth_config = AuthConfig::load();
-        let mut room_message_buffer = Vec::with_capacity(16);
-        let mut in_message_buffer = Vec::with_capacity(64);
-
-        loop {
-            tokio::select! {
-                n = in_message_rx.recv_many(&mut in_message_buffer, 64) => {
-                    if n == 0 {
-                        break;
-                    }
-
-                    for in_message in in_message_buffer.drain(0..n) {
-                        handle_in_message(&server_ctx, &auth_config, in_message).await;
-                    }
-                },
-                n = room_message_rx.recv_many(&mut room_message_buffer, 16) => {
-                    if n == 0 {
-                        break;
-                    }
-
-                    for room_message in room_message_buffer.drain(0..n) {
-                        handle_room_message(&ctx_handle, room_message).await;
-                    }
-                },
-                _ = shutdown_rx.recv() => break,
-            }
-        }
-    });
-
-    ctx
+    run_room(builder, server_ctx, shutdown_rx)
 }
 
-async fn handle_in_message(
+fn handle_in_message(
+    message: &InMessage,
     server_ctx: &Arc<ServerContext>,
-    auth_config: &AuthConfig,
-    message: InMessage
-) {
+    world: &mut World,
+) -> InMessageHandleResult {
     let (session_ctx, category, data) = message;
-    if category != ProtocolCategory::Auth {
+    if *category != ProtocolCategory::Auth {
         eprintln!("Protocol category not auth: {:?}", category);
-        _ = session_ctx.close_tx.send(());
-        return;
+        session_ctx.close(CloseReason::ProtocolError);
+        return InMessageHandleResult::Break;
     }
 
-    let protocol = AuthClientProtocol::decode(data);
+    let protocol = AuthClientProtocol::decode(data.clone());
     if let Err(e) = protocol {
         eprintln!("Failed to decode auth protocol: {}", e);
-        _ = session_ctx.close_tx.send(());
-        return;
+        session_ctx.close(CloseReason::ProtocolError);
+        return InMessageHandleResult::Break;
     }
 
     match protocol.unwrap().protocol {
         Some(Protocol::Login(login)) => {
-            handle_login(&server_ctx, auth_config, session_ctx, login).await;
+            // Already handed over to the server, or closing with a bad token
+            if !session_ctx.begin_authentication() {
+                eprintln!("Repeated login from {}", session_ctx);
+                return InMessageHandleResult::Break;
+            }
+
+            if handle_login(server_ctx, world.resource::<AuthConfig>(), session_ctx, login) {
+                // Handed over to the server, to be resumed in the player's room
+                world.resource_mut::<SessionRegistry>().remove(session_ctx);
+            }
         }
         None => {
-            _ = session_ctx.close_tx.send(());
+            session_ctx.close(CloseReason::ProtocolError);
         }
     }
+
+    InMessageHandleResult::Break
 }
 
-async fn handle_login(
+fn handle_login(
     server_ctx: &Arc<ServerContext>,
     auth_config: &AuthConfig,
-    session_ctx: Arc<SessionContext>,
+    session_ctx: &SessionContext,
     login: Login,
-) {
-    //TODO: Initialize DecodingKey once, and reuse
+) -> bool {
     let claims = match decode::<Claims>(
         &login.token,
         &auth_config.key,
@@ -105,8 +85,8 @@ async fn handle_login(
         Ok(data) => data.claims,
         Err(e) => {
             eprintln!("Error decoding token({}): {}", &login.token, e);
-            session_ctx.close().await;
-            return;
+            session_ctx.close(CloseReason::BadToken);
+            return false;
         }
     };
 
@@ -114,23 +94,23 @@ async fn handle_login(
         Ok(id) => id,
         _ => {
             eprintln!("Invalid account id: {}", claims.aid);
-            session_ctx.close().await;
-            return;
+            session_ctx.close(CloseReason::BadToken);
+            return false;
         }
     };
     let character_id: u64 = match claims.cid.parse() {
         Ok(id) => id,
         _ => {
             eprintln!("Invalid character id: {}", claims.cid);
-            session_ctx.close().await;
-            return;
+            session_ctx.close(CloseReason::BadToken);
+            return false;
         }
     };
     let privilege = match Privilege::from_str(claims.prv.as_str()) {
         Err(_) => {
             eprintln!("Invalid privilege: {}", claims.prv);
-            session_ctx.close().await;
-            return;
+            session_ctx.close(CloseReason::BadToken);
+            return false;
         },
         Ok(privilege) => privilege
     };
@@ -138,9 +118,57 @@ async fn handle_login(
     println!("Authenticated: {}", session_ctx);
 
     let account = Account {account_id, privilege};
-    _ = server_ctx.message_tx.send(ServerMessage::SessionAuthenticated {
-        session_ctx,
-        account,
-        character_id
-    }).await;
+    let session_ctx = session_ctx.clone();
+    let server_ctx = server_ctx.clone();
+    tokio::spawn(async move {
+        let Some(session) = session_ctx.retrieve().await else {
+            return;
+        };
+
+        let message = ServerMessage::SessionAuthenticated {
+            session,
+            account,
+            character_id
+        };
+        _ = server_ctx.message_tx.send(message).await;
+    });
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use jsonwebtoken::{DecodingKey, EncodingKey, Header, encode};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_login_twice() {
+        let secret = b"secret";
+        let mut world = World::default();
+        world.init_resource::<SessionRegistry>();
+        world.insert_resource(AuthConfig { key: DecodingKey::from_secret(secret) });
+
+        let (close_tx, mut close_rx) = mpsc::channel(1);
+        let (retrieve_tx, mut retrieve_rx) = mpsc::channel(2);
+        let session_ctx = SessionContext::new(SocketAddr::from(([127, 0, 0, 1], 0)), close_tx, retrieve_tx);
+        let (server_message_tx, _) = mpsc::channel(1);
+        let server_ctx = Arc::new(ServerContext::new(server_message_tx));
+
+        let claims = serde_json::json!({ "aid": "1", "cid": "2", "prv": "None", "exp": u32::MAX });
+        let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(secret)).unwrap();
+        let protocol = AuthClientProtocol { protocol: Some(Protocol::Login(Login { token })) };
+        let message = (session_ctx, ProtocolCategory::Auth, Bytes::from(protocol.encode_to_vec()));
+        handle_in_message(&message, &server_ctx, &mut world);
+        handle_in_message(&message, &server_ctx, &mut world);
+
+        // Retrieved only once, and the session is kept open for the first login
+        assert!(retrieve_rx.recv().await.is_some());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(retrieve_rx.try_recv().is_err());
+        assert!(close_rx.try_recv().is_err());
+    }
 }
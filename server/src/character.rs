@@ -10,9 +10,10 @@ pub mod vision;
 
 use bevy_ecs::prelude::*;
 use postgres_types::{FromSql, ToSql};
+use serde::Deserialize;
 use tokio_postgres::{Client, Error};
 
-#[derive(Debug, FromSql, ToSql)]
+#[derive(Debug, FromSql, ToSql, Deserialize, Eq, PartialEq, Hash, Copy, Clone)]
 #[postgres(name = "race")]
 pub enum Race {
     Human,
@@ -0,0 +1,141 @@
+use bevy_ecs::prelude::*;
+use crate::character::movement::{MovementController, MovementMode, MovementState};
+use crate::physics::collision::raycast_shape;
+use crate::physics::object::{StaticBody, Transform};
+use crate::world::chunk::ChunkGrid;
+use nalgebra::Point2;
+
+/// Each wall between the source and the listener muffles the sound by this ratio.
+const WALL_DAMPING: f32 = 0.5;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum SoundKind {
+    Footstep,
+    Combat,
+    Speech,
+}
+
+/// A sound made at `position`. `loudness` is the distance the sound reaches in the open.
+#[derive(Event, Clone)]
+pub struct Sound {
+    pub source: Entity,
+    pub position: Point2<f32>,
+    pub kind: SoundKind,
+    pub loudness: f32,
+}
+
+pub struct HeardSound {
+    pub source: Entity,
+    pub position: Point2<f32>,
+    pub kind: SoundKind,
+    pub volume: f32,
+}
+
+#[derive(Component)]
+pub struct Hearing {
+    pub threshold: f32,
+    heard: Vec<HeardSound>,
+}
+
+impl Hearing {
+    pub fn new(threshold: f32) -> Self {
+        Hearing { threshold, heard: Vec::new() }
+    }
+
+    /// Sounds heard on the last update.
+    pub fn heard(&self) -> &[HeardSound] {
+        &self.heard
+    }
+}
+
+impl Default for Hearing {
+    fn default() -> Self {
+        Hearing::new(1.0)
+    }
+}
+
+pub fn volume(loudness: f32, distance: f32, walls: usize) -> f32 {
+    (loudness - distance).max(0.0) * WALL_DAMPING.powi(walls as i32)
+}
+
+pub fn emit_footsteps(
+    query: Query<(Entity, &MovementController, &Transform)>,
+    mut sounds: EventWriter<Sound>,
+) {
+    query.iter().for_each(|(entity, controller, transform)| {
+        let loudness = match controller.state() {
+            MovementState::Walking => 6.0,
+            MovementState::Running => 12.0,
+            MovementState::Rolling => 8.0,
+            MovementState::Idle => return,
+        };
+        let multiplier = match controller.mode() {
+            MovementMode::Standing => 1.0,
+            MovementMode::Crouching => 0.5,
+            MovementMode::Crawling => 0.25,
+            MovementMode::Swimming => 0.75,
+            MovementMode::Flying => return,
+        };
+
+        sounds.send(Sound {
+            source: entity,
+            position: transform.position,
+            kind: SoundKind::Footstep,
+            loudness: loudness * multiplier,
+        });
+    });
+}
+
+pub fn update_hearing(
+    grid: Res<ChunkGrid>,
+    mut sounds: EventReader<Sound>,
+    mut listeners: Query<(Entity, &Transform, &mut Hearing)>,
+    walls: Query<(&Transform, &StaticBody)>,
+) {
+    let sounds: Vec<&Sound> = sounds.read().collect();
+
+    listeners.iter_mut().for_each(|(listener, transform, mut hearing)| {
+        hearing.heard.clear();
+
+        for sound in &sounds {
+            if sound.source == listener {
+                continue;
+            }
+
+            let distance = nalgebra::distance(&sound.position, &transform.position);
+            if volume(sound.loudness, distance, 0) < hearing.threshold {
+                continue;
+            }
+
+            let path = transform.position - sound.position;
+            let wall_count = grid.entities_around(&transform.position, distance)
+                .filter_map(|entity| walls.get(*entity).ok())
+                .filter(|(wall, body)| raycast_shape(sound.position, path, wall.position, &body.shape).is_some())
+                .count();
+
+            let volume = volume(sound.loudness, distance, wall_count);
+            if volume < hearing.threshold {
+                continue;
+            }
+
+            hearing.heard.push(HeardSound {
+                source: sound.source,
+                position: sound.position,
+                kind: sound.kind,
+                volume,
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_volume() {
+        assert_eq!(volume(10.0, 4.0, 0), 6.0);
+        assert_eq!(volume(10.0, 4.0, 2), 1.5);
+        assert_eq!(volume(10.0, 12.0, 0), 0.0);
+    }
+}
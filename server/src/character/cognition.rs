@@ -0,0 +1,289 @@
+use bevy_ecs::prelude::*;
+use crate::character::audition::Hearing;
+use crate::character::combat::{CombatCommand, CombatController};
+use crate::character::movement::{MovementCommand, MovementController};
+use crate::character::resource::Health;
+use crate::character::vision::Vision;
+use crate::physics::object::Transform;
+use crate::player::account::Account;
+use nalgebra::{Point2, UnitVector2, Vector2};
+
+/// Distance to a waypoint or a sound source considered as arrived.
+const ARRIVAL_DISTANCE: f32 = 1.0;
+/// Direction changes smaller than this(cosine) are not issued again, to avoid flooding the sync.
+const DIRECTION_TOLERANCE: f32 = 0.98;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Behaviour {
+    Idle,
+    Patrol,
+    Investigate { position: Point2<f32> },
+    Chase { target: Entity },
+    Flee { threat: Entity },
+}
+
+#[derive(Copy, Clone)]
+enum Issued {
+    Halt,
+    Walk(UnitVector2<f32>),
+    Run(UnitVector2<f32>),
+}
+
+/// Utility based AI of non-player characters. Each tick, the behaviour with the highest score is taken.
+#[derive(Component)]
+pub struct Cognition {
+    pub aggression: f32,
+    pub courage: f32,
+    pub attack_range: f32,
+    pub patrol: Vec<Point2<f32>>,
+    patrol_index: usize,
+    behaviour: Behaviour,
+    issued: Option<Issued>,
+}
+
+impl Cognition {
+    pub fn new(aggression: f32, courage: f32, attack_range: f32) -> Self {
+        Cognition {
+            aggression,
+            courage,
+            attack_range,
+            patrol: Vec::new(),
+            patrol_index: 0,
+            behaviour: Behaviour::Idle,
+            issued: None,
+        }
+    }
+
+    pub fn with_patrol(mut self, patrol: Vec<Point2<f32>>) -> Self {
+        self.patrol = patrol;
+        self
+    }
+
+    pub fn behaviour(&self) -> Behaviour {
+        self.behaviour
+    }
+}
+
+/// What a character perceived on this tick.
+#[derive(Default)]
+pub struct Perception {
+    /// Hostile entities in sight, with their distances.
+    pub hostiles: Vec<(Entity, f32)>,
+    /// The loudest sound heard, and its volume.
+    pub loudest_sound: Option<(Point2<f32>, f32)>,
+    pub health_ratio: f32,
+}
+
+pub fn decide(cognition: &Cognition, perception: &Perception) -> Behaviour {
+    let mut best = (Behaviour::Idle, 0.05);
+    let mut consider = |behaviour: Behaviour, score: f32| {
+        if score > best.1 {
+            best = (behaviour, score);
+        }
+    };
+
+    if !cognition.patrol.is_empty() {
+        consider(Behaviour::Patrol, 0.1);
+    }
+
+    if let Some((position, volume)) = perception.loudest_sound {
+        consider(Behaviour::Investigate { position }, 0.3 * volume.min(1.0));
+    }
+
+    let nearest = perception.hostiles.iter()
+        .min_by(|a, b| a.1.total_cmp(&b.1));
+    if let Some(&(hostile, distance)) = nearest {
+        let proximity = 1.0 / (1.0 + distance);
+        consider(Behaviour::Chase { target: hostile }, 0.5 + cognition.aggression * proximity);
+
+        let fear = (1.0 - perception.health_ratio) * (1.0 - cognition.courage);
+        consider(Behaviour::Flee { threat: hostile }, 2.0 * fear);
+    }
+
+    best.0
+}
+
+pub fn update(
+    mut npcs: Query<(
+        &mut Cognition,
+        &Transform,
+        &mut MovementController,
+        Option<&mut CombatController>,
+        Option<&Vision>,
+        Option<&Hearing>,
+        Option<&Health>)>,
+    hostiles: Query<&Transform, With<Account>>,
+) {
+    npcs.iter_mut().for_each(
+        |(mut cognition, transform, mut movement, combat, vision, hearing, health)| {
+        let position = transform.position;
+
+        let perception = Perception {
+            hostiles: vision.map(|vision| vision.sight()).unwrap_or_default().iter()
+                .filter_map(|entity| hostiles.get(*entity).ok().map(|t| (*entity, t.position)))
+                .map(|(entity, p)| (entity, nalgebra::distance(&position, &p)))
+                .collect(),
+            loudest_sound: hearing.and_then(|hearing| hearing.heard().iter()
+                .max_by(|a, b| a.volume.total_cmp(&b.volume))
+                .map(|sound| (sound.position, sound.volume / hearing.threshold))),
+            health_ratio: health.map(|health| health.ratio()).unwrap_or(1.0),
+        };
+
+        let behaviour = decide(&cognition, &perception);
+        cognition.behaviour = behaviour;
+
+        let mut attack = None;
+        let issue = match behaviour {
+            Behaviour::Idle => Issued::Halt,
+            Behaviour::Patrol => {
+                let waypoint = cognition.patrol[cognition.patrol_index % cognition.patrol.len()];
+                if nalgebra::distance(&position, &waypoint) <= ARRIVAL_DISTANCE {
+                    cognition.patrol_index = (cognition.patrol_index + 1) % cognition.patrol.len();
+                }
+                walk_toward(position, waypoint, Issued::Walk)
+            }
+            Behaviour::Investigate { position: source } => walk_toward(position, source, Issued::Walk),
+            Behaviour::Chase { target } => {
+                let target_position = hostiles.get(target).unwrap().position;
+                if nalgebra::distance(&position, &target_position) <= cognition.attack_range {
+                    attack = Some(target);
+                    Issued::Halt
+                } else {
+                    walk_toward(position, target_position, Issued::Run)
+                }
+            }
+            Behaviour::Flee { threat } => {
+                let threat_position = hostiles.get(threat).unwrap().position;
+                run_away(position, threat_position)
+            }
+        };
+
+        if let (Some(target), Some(mut combat)) = (attack, combat) {
+            combat.set_command(CombatCommand::Attack { target });
+        }
+
+        if !is_issued(cognition.issued, issue) {
+            cognition.issued = Some(issue);
+            movement.push_command(match issue {
+                Issued::Halt => MovementCommand::Halt,
+                Issued::Walk(direction) => MovementCommand::Walk { direction },
+                Issued::Run(direction) => MovementCommand::Run { direction },
+            });
+        }
+    });
+}
+
+fn walk_toward(
+    from: Point2<f32>,
+    to: Point2<f32>,
+    issue: fn(UnitVector2<f32>) -> Issued,
+) -> Issued {
+    let delta: Vector2<f32> = to - from;
+    if delta.norm() <= ARRIVAL_DISTANCE {
+        return Issued::Halt;
+    }
+
+    match UnitVector2::try_new(delta, f32::EPSILON) {
+        Some(direction) => issue(direction),
+        None => Issued::Halt,
+    }
+}
+
+/// Runs directly away from the threat, however close it is.
+fn run_away(from: Point2<f32>, threat: Point2<f32>) -> Issued {
+    let direction = UnitVector2::try_new(from - threat, f32::EPSILON)
+        .unwrap_or_else(Vector2::x_axis);
+    Issued::Run(direction)
+}
+
+fn is_issued(issued: Option<Issued>, issue: Issued) -> bool {
+    match (issued, issue) {
+        (Some(Issued::Halt), Issued::Halt) => true,
+        (Some(Issued::Walk(a)), Issued::Walk(b)) | (Some(Issued::Run(a)), Issued::Run(b)) => {
+            a.dot(&b) >= DIRECTION_TOLERANCE
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::character::vision::{self, SightEntered, SightLeft, Visibility};
+    use crate::physics::collision::CollisionShape;
+    use crate::physics::object::KinematicBody;
+    use crate::player::account::Privilege;
+    use crate::player::PLAYER_RADIUS;
+    use crate::world::chunk::{self, ChunkGrid};
+    use bevy_ecs::event::EventRegistry;
+    use bevy_ecs::system::RunSystemOnce;
+
+    #[test]
+    fn test_decide() {
+        let cognition = Cognition::new(1.0, 0.5, 1.5)
+            .with_patrol(vec![Point2::new(0.0, 0.0), Point2::new(10.0, 0.0)]);
+        let hostile = Entity::from_raw(0);
+
+        let perception = Perception { health_ratio: 1.0, ..Default::default() };
+        assert_eq!(decide(&cognition, &perception), Behaviour::Patrol);
+
+        let perception = Perception {
+            loudest_sound: Some((Point2::new(3.0, 3.0), 1.0)),
+            health_ratio: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(decide(&cognition, &perception), Behaviour::Investigate { position: Point2::new(3.0, 3.0) });
+
+        let perception = Perception {
+            hostiles: vec![(hostile, 4.0)],
+            health_ratio: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(decide(&cognition, &perception), Behaviour::Chase { target: hostile });
+
+        let perception = Perception {
+            hostiles: vec![(hostile, 4.0)],
+            health_ratio: 0.1,
+            ..Default::default()
+        };
+        assert_eq!(decide(&cognition, &perception), Behaviour::Flee { threat: hostile });
+    }
+
+    #[test]
+    fn test_run_away() {
+        let Issued::Run(direction) = run_away(Point2::new(1.0, 0.5), Point2::new(1.0, 0.0)) else {
+            panic!("Run expected");
+        };
+        assert!((direction.into_inner() - Vector2::y()).norm() < 1e-6);
+
+        assert!(matches!(run_away(Point2::new(1.0, 0.0), Point2::new(1.0, 0.0)), Issued::Run(_)));
+    }
+
+    #[test]
+    fn test_update_chase_player() {
+        let mut world = World::default();
+        world.init_resource::<ChunkGrid>();
+        EventRegistry::register_event::<SightEntered>(&mut world);
+        EventRegistry::register_event::<SightLeft>(&mut world);
+
+        let npc = world.spawn((
+            Cognition::new(1.0, 0.5, 1.5),
+            Transform::default(),
+            MovementController::default(),
+            Vision::cone(10.0, 0.5, 5),
+        )).id();
+        // The components of a PlayerBundle that the NPCs perceive
+        let player = world.spawn((
+            Account { account_id: 1, privilege: Privilege::None },
+            Transform { position: Point2::new(5.0, 0.0), ..Default::default() },
+            KinematicBody { shape: CollisionShape::Circle { radius: PLAYER_RADIUS } },
+            Visibility::new(true),
+        )).id();
+
+        world.run_system_once(chunk::update).unwrap();
+        world.run_system_once(vision::update_sight).unwrap();
+        world.run_system_once(update).unwrap();
+
+        assert_eq!(world.get::<Cognition>(npc).unwrap().behaviour(), Behaviour::Chase { target: player });
+    }
+}
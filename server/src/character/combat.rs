@@ -1,10 +1,361 @@
 use bevy_ecs::prelude::*;
+use crate::character::audition::{Sound, SoundKind};
+use crate::character::movement::MovementController;
+use crate::character::resource::{Dead, Health, Mana};
+use crate::character::stat::{CharacterStat, CombatStat};
+use crate::character::status_effect::StatusEffectController;
+use crate::core::room_resource::SessionRegistry;
+use crate::physics::collision::{contact, raycast_shape, CollisionShape};
+use crate::physics::object::{KinematicBody, StaticBody, Transform};
+use crate::protocol::*;
+use crate::protocol::game::*;
+use crate::world::chunk::ChunkGrid;
+use crate::world::interest::Interest;
+use crate::world::time::WorldTime;
+use std::time::{Duration, Instant};
+
+const ATTACK_WINDUP: Duration = Duration::from_millis(300);
+const ATTACK_REACH: f32 = 1.5;
+const BLOCK_MULTIPLIER: f32 = 0.25;
+const VULNERABLE_MULTIPLIER: f32 = 1.5;
+const HIT_LOUDNESS: f32 = 15.0;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Skill {
+    PowerStrike,
+    Fireball,
+}
+
+impl Skill {
+    pub fn cast_time(&self) -> Duration {
+        match self {
+            Skill::PowerStrike => Duration::from_millis(500),
+            Skill::Fireball => Duration::from_millis(1000),
+        }
+    }
+
+    pub fn range(&self) -> f32 {
+        match self {
+            Skill::PowerStrike => ATTACK_REACH,
+            Skill::Fireball => 12.0,
+        }
+    }
+
+    pub fn multiplier(&self) -> f32 {
+        match self {
+            Skill::PowerStrike => 2.0,
+            Skill::Fireball => 1.5,
+        }
+    }
+
+    pub fn is_ranged(&self) -> bool {
+        matches!(self, Skill::Fireball)
+    }
+
+    pub fn is_magic(&self) -> bool {
+        matches!(self, Skill::Fireball)
+    }
+
+    pub fn mana_cost(&self) -> u32 {
+        match self {
+            Skill::PowerStrike => 0,
+            Skill::Fireball => 20,
+        }
+    }
+}
 
 pub enum CombatCommand {
     None,
+    Attack { target: Entity },
+    Cast { skill: Skill, target: Entity },
+    Block,
+    Cancel,
+}
+
+#[derive(Copy, Clone)]
+pub enum CombatState {
+    Idle,
+    Attacking { target: Entity, then: Instant },
+    Casting { skill: Skill, target: Entity, then: Instant },
+    Blocking,
 }
 
 #[derive(Component)]
 pub struct CombatController {
     command: CombatCommand,
-}
\ No newline at end of file
+    state: CombatState,
+}
+
+impl CombatController {
+    pub fn set_command(&mut self, command: CombatCommand) {
+        self.command = command;
+    }
+
+    pub fn state(&self) -> CombatState {
+        self.state
+    }
+
+    pub fn is_blocking(&self) -> bool {
+        matches!(self.state, CombatState::Blocking)
+    }
+}
+
+impl Default for CombatController {
+    fn default() -> Self {
+        CombatController { command: CombatCommand::None, state: CombatState::Idle }
+    }
+}
+
+/// An attack or a skill landing on this tick. Hit detection is done on resolution.
+#[derive(Event)]
+pub struct Strike {
+    pub attacker: Entity,
+    pub target: Entity,
+    pub power: f32,
+    pub reach: f32,
+    pub ranged: bool,
+}
+
+#[derive(Event)]
+pub struct Hit {
+    pub attacker: Entity,
+    pub target: Entity,
+    pub damage: u32,
+}
+
+pub fn power(attack: u32, scaling_stat: u16, multiplier: f32) -> f32 {
+    (attack as f32 + scaling_stat as f32 * 2.0) * multiplier
+}
+
+pub fn damage(power: f32, constitution: u16, blocking: bool, vulnerable: bool) -> u32 {
+    let mut damage = power * 100.0 / (100.0 + constitution as f32 * 2.0);
+    if blocking {
+        damage *= BLOCK_MULTIPLIER;
+    }
+    if vulnerable {
+        damage *= VULNERABLE_MULTIPLIER;
+    }
+
+    damage.round() as u32
+}
+
+pub fn update(
+    mut query: Query<(
+        Entity,
+        &mut CombatController,
+        &CombatStat,
+        Option<&CharacterStat>,
+        Option<&StatusEffectController>,
+        Option<&mut Mana>), Without<Dead>>,
+    time: Res<WorldTime>,
+    mut strikes: EventWriter<Strike>,
+) {
+    query.iter_mut().for_each(|(attacker, mut controller, combat_stat, character_stat, status, mana)| {
+        let command = std::mem::replace(&mut controller.command, CombatCommand::None);
+        let stunned = status.is_some_and(|status| status.is_stunned());
+        if stunned {
+            controller.state = CombatState::Idle;
+        }
+
+        let busy = matches!(controller.state, CombatState::Attacking { .. } | CombatState::Casting { .. });
+        match command {
+            CombatCommand::None => {}
+            CombatCommand::Attack { target } => if !stunned && !busy {
+                controller.state = CombatState::Attacking { target, then: time.now + ATTACK_WINDUP };
+            }
+            // Characters without mana, e.g. monsters, cast for free
+            CombatCommand::Cast { skill, target } => if !stunned && !busy
+                && mana.is_none_or(|mut mana| mana.spend(skill.mana_cost())) {
+                controller.state = CombatState::Casting { skill, target, then: time.now + skill.cast_time() };
+            }
+            CombatCommand::Block => if !stunned && !busy {
+                controller.state = CombatState::Blocking;
+            }
+            CombatCommand::Cancel => {
+                controller.state = CombatState::Idle;
+            }
+        }
+
+        let (strength, intelligence) = character_stat
+            .map(|stat| (stat.strength(), stat.intelligence()))
+            .unwrap_or_default();
+
+        match controller.state {
+            CombatState::Attacking { target, then } if time.now >= then => {
+                strikes.send(Strike {
+                    attacker,
+                    target,
+                    power: power(combat_stat.attack, strength, 1.0),
+                    reach: ATTACK_REACH,
+                    ranged: false,
+                });
+                controller.state = CombatState::Idle;
+            }
+            CombatState::Casting { skill, target, then } if time.now >= then => {
+                let scaling_stat = if skill.is_magic() { intelligence } else { strength };
+                strikes.send(Strike {
+                    attacker,
+                    target,
+                    power: power(combat_stat.attack, scaling_stat, skill.multiplier()),
+                    reach: skill.range(),
+                    ranged: skill.is_ranged(),
+                });
+                controller.state = CombatState::Idle;
+            }
+            _ => {}
+        }
+    });
+}
+
+pub fn resolve(
+    mut strikes: EventReader<Strike>,
+    grid: Res<ChunkGrid>,
+    attackers: Query<&Transform>,
+    mut targets: Query<(
+        &Transform,
+        &KinematicBody,
+        &mut Health,
+        Option<&CharacterStat>,
+        Option<&StatusEffectController>,
+        Option<&CombatController>,
+        Option<&MovementController>), Without<Dead>>,
+    walls: Query<(&Transform, &StaticBody)>,
+    time: Res<WorldTime>,
+    observers: Query<(Entity, &Interest)>,
+    sessions: Res<SessionRegistry>,
+    mut hits: EventWriter<Hit>,
+    mut sounds: EventWriter<Sound>,
+) {
+    for strike in strikes.read() {
+        let Ok(attacker_transform) = attackers.get(strike.attacker) else {
+            continue;
+        };
+        let Ok((transform, body, mut health, character_stat, status, combat, movement)) = targets.get_mut(strike.target) else {
+            continue;
+        };
+
+        // Dodged by rolling
+        if movement.is_some_and(|movement| movement.is_invulnerable(time.now)) {
+            continue;
+        }
+
+        let from = attacker_transform.position;
+        let to = transform.position;
+        let reach = CollisionShape::Circle { radius: strike.reach };
+        if contact(from, &reach, to, &body.shape).is_none() {
+            continue;
+        }
+
+        if strike.ranged {
+            let path = to - from;
+            let occluded = grid.entities_around(&to, nalgebra::distance(&from, &to))
+                .filter_map(|entity| walls.get(*entity).ok())
+                .any(|(wall, wall_body)| raycast_shape(from, path, wall.position, &wall_body.shape).is_some());
+            if occluded {
+                continue;
+            }
+        }
+
+        let constitution = character_stat.map(|stat| stat.constitution()).unwrap_or_default();
+        let blocking = combat.is_some_and(|combat| combat.is_blocking());
+        let stunned = status.is_some_and(|status| status.is_stunned());
+        let amount = damage(strike.power, constitution, blocking, stunned);
+        let dealt = health.damage(amount);
+
+        hits.send(Hit { attacker: strike.attacker, target: strike.target, damage: dealt });
+        sounds.send(Sound {
+            source: strike.target,
+            position: to,
+            kind: SoundKind::Combat,
+            loudness: HIT_LOUDNESS,
+        });
+
+        let protocol = GameServerProtocol {
+            protocol: Some(game_server_protocol::Protocol::Damage(Damage {
+                attacker: strike.attacker.to_bits(),
+                target: strike.target.to_bits(),
+                amount: dealt,
+                blocked: blocking,
+            }))
+        };
+        let buf = match serialize_protocol(ProtocolCategory::Game, &protocol) {
+            Ok(buf) => buf,
+            Err(e) => {
+                eprintln!("Failed to serialize damage: {}", e);
+                continue;
+            }
+        };
+
+        let recipients: Vec<Entity> = observers.iter()
+            .filter(|(_, interest)| interest.is_relevant(strike.target))
+            .map(|(observer, _)| observer)
+            .collect();
+        sessions.send_filtered(buf, |entity| recipients.contains(&entity));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::character::audition::Sound;
+    use crate::player::PLAYER_RADIUS;
+    use crate::world::chunk;
+    use bevy_ecs::event::EventRegistry;
+    use bevy_ecs::system::RunSystemOnce;
+    use nalgebra::Point2;
+
+    #[test]
+    fn test_damage() {
+        let power = power(10, 5, 2.0);
+        assert_eq!(power, 40.0);
+
+        assert_eq!(damage(power, 0, false, false), 40);
+        assert_eq!(damage(power, 50, false, false), 20);
+        assert_eq!(damage(power, 50, true, false), 5);
+        assert_eq!(damage(power, 50, false, true), 30);
+    }
+
+    #[test]
+    fn test_cast() {
+        let mut world = World::default();
+        world.init_resource::<ChunkGrid>();
+        world.init_resource::<SessionRegistry>();
+        world.init_resource::<WorldTime>();
+        EventRegistry::register_event::<Strike>(&mut world);
+        EventRegistry::register_event::<Hit>(&mut world);
+        EventRegistry::register_event::<Sound>(&mut world);
+
+        let caster = world.spawn((
+            CombatController::default(),
+            CombatStat::new(10),
+            Mana::new(30),
+            Transform::default(),
+        )).id();
+        // The components of a PlayerBundle that can be hit
+        let target = world.spawn((
+            Transform { position: Point2::new(10.0, 0.0), ..Default::default() },
+            KinematicBody { shape: CollisionShape::Circle { radius: PLAYER_RADIUS } },
+            Health::new(100),
+        )).id();
+        world.run_system_once(chunk::update).unwrap();
+
+        let cast = |world: &mut World| {
+            world.get_mut::<CombatController>(caster).unwrap()
+                .set_command(CombatCommand::Cast { skill: Skill::Fireball, target });
+            world.run_system_once(update).unwrap();
+            world.resource_mut::<WorldTime>().now += Skill::Fireball.cast_time();
+            world.run_system_once(update).unwrap();
+            world.run_system_once(resolve).unwrap();
+            world.resource_mut::<Events<Strike>>().clear();
+        };
+
+        cast(&mut world);
+        assert_eq!(world.get::<Mana>(caster).unwrap().value(), 10);
+        assert_eq!(world.get::<Health>(target).unwrap().value(), 85);
+
+        // Not enough mana left
+        cast(&mut world);
+        assert_eq!(world.get::<Mana>(caster).unwrap().value(), 10);
+        assert_eq!(world.get::<Health>(target).unwrap().value(), 85);
+    }
+}
@@ -1,18 +1,58 @@
+pub mod tuning;
+
 use bevy_ecs::prelude::*;
+use crate::character::Character;
+use crate::character::movement::tuning::{MovementMultipliers, MovementTuning};
+use crate::character::resource::{Regeneration, Stamina};
 use crate::character::stat::MobilityStat;
 use crate::character::status_effect::StatusEffectController;
-use crate::physics::object::Transform;
+use crate::core::room_resource::SessionRegistry;
+use crate::core::session::Latency;
+use crate::physics::collision::{contact, raycast_shape};
+use crate::physics::object::{KinematicBody, StaticBody, Transform};
+use crate::player::account::Account;
 use crate::protocol::*;
-use crate::protocol::game::*;
+use crate::protocol::game::{GameServerProtocol, Movement, MovementSync, game_server_protocol};
+use crate::world::chunk::ChunkGrid;
+use crate::world::environment::Environment;
+use crate::world::interest::Interest;
 use crate::world::time::WorldTime;
 use nalgebra::{Point2, UnitVector2, Vector2};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 use crate::character::movement::MovementState::*;
 use crate::character::movement::MovementMode::*;
 use crate::character::movement::MovementInterpolation::*;
 use crate::character::movement::MovementCommand::*;
 
-type Transition = (MovementState, std::time::Instant);
+type Transition = (MovementState, Instant);
+/// Position last claimed by the client, and the time moving there at full speed has taken up.
+type Claim = (Point2<f32>, Instant);
+
+/// Stamina per second drained while swimming or flying, and regenerated otherwise.
+const SWIM_STAMINA_DRAIN: f32 = 3.0;
+const FLY_STAMINA_DRAIN: f32 = 8.0;
+const STAMINA_REGENERATION: f32 = 5.0;
+
+/// Commands queued between the ticks, beyond which a client is flooding.
+const MAX_COMMANDS: usize = 32;
+
+const ROLL_DISTANCE: f32 = 4.0;
+const ROLL_DURATION: Duration = Duration::from_millis(500);
+/// Rolling characters can't be hit from the start of the roll until this elapses.
+const ROLL_INVULNERABILITY: Duration = Duration::from_millis(300);
+
+/// How far ahead of the time since the last claim a client may claim to be, as time of moving
+/// at full speed. Half of the round trip time of the client is allowed on top of it.
+const DRIFT_ALLOWANCE: Duration = Duration::from_millis(250);
+/// Upper bound of the allowance for the round trip time, which the client can inflate by delaying pongs.
+const MAX_LAG_COMPENSATION: Duration = Duration::from_millis(250);
+/// How long ago the last claim may be measured from, so that idling doesn't bank time for a jump.
+/// A few ticks are allowed on top of the lag compensation, for the claims delayed by the network.
+const MAX_CLAIM_WINDOW: Duration = Duration::from_millis(150).saturating_add(MAX_LAG_COMPENSATION);
+/// Drift always allowed regardless of the speed, for the rounding and the collision corrections.
+const DRIFT_TOLERANCE: f32 = 0.5;
 
 #[derive(Eq, PartialEq, Copy, Clone, Default)]
 pub enum MovementState {
@@ -58,6 +98,12 @@ pub enum MovementCommand {
     Teleport { position: Point2<f32>, forced: bool },
 }
 
+struct RollProgress {
+    started: Instant,
+    // Distance covered so far
+    covered: f32,
+}
+
 #[derive(Component, Default)]
 pub struct MovementController {
     state: MovementState,
@@ -65,6 +111,163 @@ pub struct MovementController {
     commands: Vec<MovementCommand>,
     transition: Option<Transition>,
     interpolation: Option<MovementInterpolation>,
+    roll: Option<RollProgress>,
+    claim: Option<Claim>,
+}
+
+impl MovementController {
+    pub fn state(&self) -> MovementState {
+        self.state
+    }
+
+    pub fn mode(&self) -> MovementMode {
+        self.mode
+    }
+
+    /// Returns `false` without queueing if too many commands are pending.
+    pub fn push_command(&mut self, command: MovementCommand) -> bool {
+        if self.commands.len() >= MAX_COMMANDS {
+            return false;
+        }
+
+        self.commands.push(command);
+        true
+    }
+
+    pub fn is_invulnerable(&self, now: Instant) -> bool {
+        self.roll.as_ref().is_some_and(|roll| now < roll.started + ROLL_INVULNERABILITY)
+    }
+}
+
+/// Count of rejected movements per account.
+#[derive(Resource, Default)]
+pub struct MovementViolations {
+    counts: HashMap<u64, u32>,
+}
+
+impl MovementViolations {
+    pub fn record(&mut self, account_id: u64) -> u32 {
+        let count = self.counts.entry(account_id).or_default();
+        *count += 1;
+        *count
+    }
+
+    pub fn count(&self, account_id: u64) -> u32 {
+        self.counts.get(&account_id).copied().unwrap_or_default()
+    }
+}
+
+/// Validates the commands from clients before they are applied. Positions claimed by the client
+/// with non-forced teleports must be reachable at full speed since the last accepted claim, and
+/// without passing through static bodies. Rejected claims are answered with the authoritative position.
+pub fn validate(
+    mut query: Query<(
+        Entity,
+        &mut MovementController,
+        &Transform,
+        &MobilityStat,
+        Option<&KinematicBody>,
+        Option<&Account>,
+        Option<&Character>,
+        Option<&Latency>)>,
+    walls: Query<(&Transform, &StaticBody)>,
+    grid: Res<ChunkGrid>,
+    tuning: Res<MovementTuning>,
+    sessions: Res<SessionRegistry>,
+    time: Res<WorldTime>,
+    mut violations: ResMut<MovementViolations>,
+) {
+    query.iter_mut().for_each(
+        |(entity, mut controller, transform, mobility, body, account, character, latency)| {
+        if controller.commands.is_empty() {
+            return;
+        }
+
+        let multipliers = tuning.multipliers(character.map(|character| character.race));
+        let lag = latency.map(|latency| latency.rtt / 2).unwrap_or_default();
+        let allowance = DRIFT_ALLOWANCE + lag.min(MAX_LAG_COMPENSATION);
+        let speed = mobility.speed * multipliers.fastest();
+        let commands: Vec<_> = controller.bypass_change_detection().commands.drain(..).collect();
+        let mut rejected = false;
+        for command in commands {
+            let valid = match &command {
+                Walk { direction } | Run { direction } | Roll { direction } => {
+                    direction.x.is_finite() && direction.y.is_finite()
+                }
+                Teleport { position, forced: false } => {
+                    // Measured from the last claim, each claim taking up the time to move there,
+                    // so that claiming every tick can't get ahead by more than the allowance
+                    let (origin, at) = controller.claim.unwrap_or((transform.position, time.now));
+                    let at = time.now.checked_sub(MAX_CLAIM_WINDOW).map_or(at, |earliest| at.max(earliest));
+                    let window = (time.now + allowance).saturating_duration_since(at);
+                    let drift = nalgebra::distance(&origin, position);
+                    let valid = drift <= DRIFT_TOLERANCE + speed * window.as_millis() as f32
+                        && is_reachable(transform, *position, body, &walls, &grid);
+
+                    // Rejected claims continue from the authoritative position the client is corrected to
+                    let claim = if valid {
+                        let taken = Duration::try_from_secs_f32(drift / speed / 1000.0).unwrap_or_default();
+                        (*position, at + taken)
+                    } else {
+                        (transform.position, at)
+                    };
+                    controller.bypass_change_detection().claim = Some(claim);
+                    valid
+                }
+                Teleport { position, forced: true } => {
+                    controller.bypass_change_detection().claim = Some((*position, time.now));
+                    true
+                }
+                _ => true,
+            };
+
+            if valid {
+                controller.bypass_change_detection().commands.push(command);
+                continue;
+            }
+
+            rejected = true;
+            if let Some(account) = account {
+                let count = violations.record(account.account_id);
+                eprintln!("Movement rejected: account_id={}, violations={}", account.account_id, count);
+            }
+        }
+
+        if !rejected {
+            return;
+        }
+
+        // Correct the client with the authoritative position
+        let movement = to_movement(entity, &controller, transform, None);
+        let protocol = GameServerProtocol {
+            protocol: Some(game_server_protocol::Protocol::MovementSync(MovementSync { movements: vec![movement] }))
+        };
+        match serialize_protocol(ProtocolCategory::Game, &protocol) {
+            Ok(buf) => sessions.send(entity, buf),
+            Err(e) => eprintln!("Failed to serialize movement correction: {}", e),
+        }
+    });
+}
+
+fn is_reachable(
+    transform: &Transform,
+    position: Point2<f32>,
+    body: Option<&KinematicBody>,
+    walls: &Query<(&Transform, &StaticBody)>,
+    grid: &ChunkGrid,
+) -> bool {
+    let path = position - transform.position;
+    let radius = path.norm() + body.map(|body| body.shape.bounding_radius()).unwrap_or_default();
+
+    grid.entities_around(&transform.position, radius)
+        .filter_map(|entity| walls.get(*entity).ok())
+        .all(|(wall, wall_body)| {
+            let blocked = raycast_shape(transform.position, path, wall.position, &wall_body.shape).is_some();
+            let overlapped = body.is_some_and(|body| {
+                contact(position, &body.shape, wall.position, &wall_body.shape).is_some()
+            });
+            !blocked && !overlapped
+        })
 }
 
 pub fn update(
@@ -72,27 +275,63 @@ pub fn update(
         &mut MovementController,
         &mut Transform,
         &MobilityStat,
-        Option<&StatusEffectController>)>,
+        Option<&StatusEffectController>,
+        Option<&Environment>,
+        Option<&Stamina>,
+        Option<&Character>)>,
+    tuning: Res<MovementTuning>,
     time: Res<WorldTime>,
 ) {
     query.iter_mut().for_each(
-        |(mut controller, mut transform, mobility, status)| {
+        |(mut controller_ref, mut transform_ref, mobility, status, environment, stamina, character)| {
+        // Marked as changed below only when something to be synced has changed
+        let controller = controller_ref.bypass_change_detection();
+        let transform = transform_ref.bypass_change_detection();
+        let (state, mode, interpolated) = (controller.state, controller.mode, controller.interpolation.is_some());
+        let (position, rotation, velocity) = (transform.position, transform.rotation, transform.velocity);
+
         if let Some(transition) = controller.transition.take() {
-            handle_transition(transition, &mut controller, &time);
+            handle_transition(transition, controller, transform, &time);
+        }
+
+        if let Some(environment) = environment {
+            handle_environment(controller, environment, stamina);
+        }
+
+        // Stunned characters stop where they are
+        let stunned = status.is_some_and(|status| status.is_stunned());
+        if stunned && (controller.state == Walking || controller.state == Running) {
+            controller.state = Idle;
         }
 
         let commands: Vec<_> = controller.commands.drain(..).collect();
         for command in commands {
-            handle_command(command, &mut controller, &mut transform, status);
+            handle_command(command, controller, transform, status, environment, stamina, &time);
         }
 
-        handle_movement(&controller, &mut transform, &mobility, &time);
+        let multipliers = tuning.multipliers(character.map(|character| character.race));
+        handle_movement(controller, transform, mobility, multipliers, &time);
+
+        let moved = transform.position != position
+            || transform.rotation != rotation
+            || transform.velocity != velocity;
+        let changed = moved
+            || controller.state != state
+            || controller.mode != mode
+            || controller.interpolation.is_some() != interpolated;
+        if changed {
+            controller_ref.set_changed();
+        }
+        if moved {
+            transform_ref.set_changed();
+        }
     })
 }
 
 fn handle_transition(
     transition: Transition,
     controller: &mut MovementController,
+    transform: &mut Transform,
     time: &Res<WorldTime>,
 ) {
     let (state, then) = transition;
@@ -102,146 +341,488 @@ fn handle_transition(
         return;
     }
 
+    if controller.state == Rolling {
+        // Cover the rest of the roll, which may have ended between the ticks
+        handle_roll(controller, transform, time.now);
+        controller.roll = Option::None;
+    }
+
     controller.state = state;
 }
 
+/// Switches the mode when the character enters or leaves water, or can't stay in the air.
+fn handle_environment(controller: &mut MovementController, environment: &Environment, stamina: Option<&Stamina>) {
+    let exhausted = stamina.is_some_and(|stamina| stamina.is_depleted());
+    match controller.mode {
+        Flying => if !environment.can_fly() || exhausted {
+            controller.mode = if environment.is_in_water() { Swimming } else { Standing };
+        }
+        Swimming => if !environment.is_in_water() {
+            controller.mode = Standing;
+        }
+        _ => if environment.is_in_water() {
+            controller.mode = Swimming;
+        }
+    }
+}
+
 fn handle_command(
     command: MovementCommand,
     controller: &mut MovementController,
     transform: &mut Transform,
     status: Option<&StatusEffectController>,
+    environment: Option<&Environment>,
+    stamina: Option<&Stamina>,
+    time: &Res<WorldTime>,
 ) {
+    // Rolls can't be interrupted
+    let stunned = status.is_some_and(|status| status.is_stunned());
+    let locked = stunned || controller.state == Rolling;
+
     match command {
         Halt => if controller.state == Walking || controller.state == Running {
             controller.state = Idle;
         }
-        Walk { direction } => {
-            //TODO: Check status
+        Walk { direction } => if !locked {
             controller.state = Walking;
             transform.rotation = direction;
         }
-        Run { direction } => {
-            //TODO: Check status
+        Run { direction } => if !locked {
             controller.state = Running;
             transform.rotation = direction;
         }
-        Roll { direction } => {
-            //TODO: Check status
-            //TODO: Set rolling expiration as transition
+        Roll { direction } => if !locked {
             controller.state = Rolling;
+            controller.transition = Some((Idle, time.now + ROLL_DURATION));
+            controller.roll = Some(RollProgress { started: time.now, covered: 0.0 });
             transform.rotation = direction;
         }
-        Stand => {
-            //TODO: Check status
+        Stand => if !locked {
             controller.mode = Standing;
         }
-        Crouch => {
-            //TODO: Check status
+        Crouch => if !locked {
             controller.mode = Crouching;
         }
-        Crawl => {
-            //TODO: Check status
+        Crawl => if !locked {
             controller.mode = Crawling;
         }
-        Swim => {
-            //TODO: Check status
+        // Characters without an environment are always on the ground
+        Swim => if !locked && environment.is_some_and(|environment| environment.can_swim()) {
             controller.mode = Swimming;
         }
-        Fly => {
-            //TODO: Check status
-            controller.mode = Flying;
+        Fly => if !locked && environment.is_some_and(|environment| environment.can_fly()) {
+            let exhausted = stamina.is_some_and(|stamina| stamina.is_depleted());
+            if !exhausted {
+                controller.mode = Flying;
+            }
         }
         Teleport { position, forced } => {
-            controller.interpolation = Some(None); // Don't interpolate the teleportation
-
             if forced {
                 //TODO: Check if movable to the position
-                transform.position = position;
+            } else if locked {
                 return;
             }
 
-            //TODO: Check status
+            controller.interpolation = Some(None); // Don't interpolate the teleportation
             transform.position = position;
         }
     }
 }
 
 fn handle_movement(
-    controller: &MovementController,
+    controller: &mut MovementController,
     transform: &mut Transform,
     mobility: &MobilityStat,
+    multipliers: &MovementMultipliers,
     time: &Res<WorldTime>,
 ) {
-    //TODO: Extract to config?
-    const WALK_MULTIPLIER: f32 = 1.0;
-    const RUN_MULTIPLIER: f32 = 1.5;
-
-    const STAND_MULTIPLIER: f32 = 1.0;
-    const CROUCH_MULTIPLIER: f32 = 0.6;
-    const CRAWL_MULTIPLIER: f32 = 0.3;
-
     if controller.state == Idle {
         transform.velocity = Vector2::<f32>::zeros();
         return;
     }
 
     if controller.state == Rolling {
-        //TODO
+        handle_roll(controller, transform, time.now);
         return;
     }
 
-    let mut speed = mobility.speed;
-    speed *= match controller.state {
-        Walking => WALK_MULTIPLIER,
-        Running => RUN_MULTIPLIER,
-        _ => 1.0,
-    };
-    speed *= match controller.mode {
-        Standing => STAND_MULTIPLIER,
-        Crouching => CROUCH_MULTIPLIER,
-        Crawling => CRAWL_MULTIPLIER,
-        _ => 1.0,
-    };
+    let speed = mobility.speed
+        * multipliers.state(controller.state)
+        * multipliers.mode(controller.mode);
 
     let velocity = speed * (time.dt.as_millis() as f32) * (*transform.rotation);
     transform.position += velocity;
     transform.velocity = velocity;
+    reset_claim(controller, transform);
+}
+
+fn handle_roll(controller: &mut MovementController, transform: &mut Transform, now: Instant) {
+    let Some(roll) = controller.roll.as_mut() else {
+        return;
+    };
+
+    let elapsed = now.saturating_duration_since(roll.started);
+    let progress = (elapsed.as_secs_f32() / ROLL_DURATION.as_secs_f32()).min(1.0);
+    let distance = ROLL_DISTANCE * progress - roll.covered;
+    roll.covered += distance;
+
+    let velocity = distance * (*transform.rotation);
+    transform.position += velocity;
+    transform.velocity = velocity;
+    reset_claim(controller, transform);
+}
+
+/// Claims continue from where the server has moved the character to, keeping the time taken up.
+fn reset_claim(controller: &mut MovementController, transform: &Transform) {
+    if let Some((_, at)) = controller.claim {
+        controller.claim = Some((transform.position, at));
+    }
+}
+
+/// Swimming and flying drain the stamina, which regenerates in the other modes.
+pub fn drain_stamina(mut query: Query<(&MovementController, &mut Regeneration), With<Stamina>>) {
+    query.iter_mut().for_each(|(controller, mut regeneration)| {
+        let rate = match controller.mode {
+            Swimming => -SWIM_STAMINA_DRAIN,
+            Flying => -FLY_STAMINA_DRAIN,
+            _ => STAMINA_REGENERATION,
+        };
+        if regeneration.stamina != rate {
+            regeneration.stamina = rate;
+        }
+    });
 }
 
-//TODO: Add session as component?
 pub fn sync(
-    mut query: Query<(Entity, &mut MovementController, &Transform), Changed<MovementController>>,
+    mut query: Query<(Entity, &mut MovementController, &Transform)>,
+    observers: Query<(Entity, &Interest)>,
+    sessions: Res<SessionRegistry>,
 ) {
-    //TODO: initialize Vec with query size
-    let mut movements = Vec::new();
+    let mut changed = HashMap::new();
 
     query.iter_mut().for_each(|(entity, mut controller, transform)| {
-        let interpolation = if let Some(interpolation) = controller.interpolation.take() {
-            interpolation
-        } else {
-            // Default interpolation is linear
-            Linear
+        if !controller.is_changed() {
+            return;
+        }
+
+        // Taking the interpolation must not mark the controller as changed again
+        let interpolation = controller.bypass_change_detection().interpolation.take().unwrap_or(Linear);
+        changed.insert(entity, to_movement(entity, &controller, transform, interpolation));
+    });
+
+    observers.iter().for_each(|(observer, interest)| {
+        if sessions.get(observer).is_none() {
+            return;
+        }
+
+        let mut movements: Vec<Movement> = interest.relevant()
+            .filter_map(|target| changed.get(target).cloned())
+            .collect();
+
+        // Newly relevant entities are sent in full, even if they haven't changed
+        for target in interest.entered() {
+            if changed.contains_key(target) {
+                continue;
+            }
+
+            if let Ok((entity, controller, transform)) = query.get(*target) {
+                movements.push(to_movement(entity, controller, transform, None));
+            }
+        }
+
+        if movements.is_empty() {
+            return;
+        }
+
+        // Coalesced with the sync not sent yet, if the client is behind
+        sessions.send_movements(observer, movements);
+    });
+}
+
+fn to_movement(
+    entity: Entity,
+    controller: &MovementController,
+    transform: &Transform,
+    interpolation: MovementInterpolation,
+) -> Movement {
+    Movement {
+        entity: entity.to_bits(),
+        state: controller.state as i32,
+        mode: controller.mode as i32,
+        interpolation: interpolation as i32,
+        position: Some(transform.position.into()),
+        velocity: Some(transform.velocity.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::collision::CollisionShape;
+    use crate::player::account::Privilege;
+    use crate::physics::object::TriggerBody;
+    use crate::world::chunk;
+    use crate::world::environment::{self, Medium, Region};
+    use bevy_ecs::system::RunSystemOnce;
+
+    #[test]
+    fn test_roll() {
+        let mut world = World::default();
+        let now = Instant::now();
+        world.insert_resource(WorldTime { now, dt: Duration::default() });
+        world.init_resource::<MovementTuning>();
+
+        let mut controller = MovementController::default();
+        controller.push_command(Roll { direction: UnitVector2::new_normalize(Vector2::new(0.0, 1.0)) });
+        let entity = world.spawn((controller, Transform::default(), MobilityStat::new(0.01))).id();
+
+        let tick = |world: &mut World, elapsed: Duration| {
+            let mut time = world.resource_mut::<WorldTime>();
+            time.dt = now + elapsed - time.now;
+            time.now = now + elapsed;
+            world.run_system_once(update).unwrap();
         };
 
-        let movement = Movement {
-            entity: entity.to_bits(),
-            state: controller.state as i32,
-            mode: controller.mode as i32,
-            interpolation: interpolation as i32,
-            position: Some(transform.position.into()),
-            velocity: Some(transform.velocity.into()),
+        tick(&mut world, Duration::ZERO);
+        // Blocked while rolling
+        world.get_mut::<MovementController>(entity).unwrap()
+            .push_command(Walk { direction: UnitVector2::new_normalize(Vector2::new(1.0, 0.0)) });
+        tick(&mut world, Duration::from_millis(250));
+        let controller = world.get::<MovementController>(entity).unwrap();
+        assert!(controller.state() == Rolling);
+        assert!(controller.is_invulnerable(now + Duration::from_millis(250)));
+        assert_eq!(world.get::<Transform>(entity).unwrap().position, Point2::new(0.0, ROLL_DISTANCE / 2.0));
+
+        // The roll ended between the ticks
+        tick(&mut world, Duration::from_millis(600));
+        let controller = world.get::<MovementController>(entity).unwrap();
+        assert!(controller.state() == Idle);
+        assert!(!controller.is_invulnerable(now + Duration::from_millis(600)));
+        assert_eq!(world.get::<Transform>(entity).unwrap().position, Point2::new(0.0, ROLL_DISTANCE));
+    }
+
+    #[test]
+    fn test_update_unchanged() {
+        let mut world = World::default();
+        world.init_resource::<WorldTime>();
+        world.init_resource::<MovementTuning>();
+        let entity = world.spawn((MovementController::default(), Transform::default(), MobilityStat::new(0.01))).id();
+        world.run_system_once(update).unwrap();
+
+        // Idle entities are not synced again
+        let tick = world.change_tick();
+        world.run_system_once(update).unwrap();
+        let this_run = world.change_tick();
+        let entity = world.entity(entity);
+        assert!(!entity.get_ref::<MovementController>().unwrap().last_changed().is_newer_than(tick, this_run));
+        assert!(!entity.get_ref::<Transform>().unwrap().last_changed().is_newer_than(tick, this_run));
+    }
+
+    #[test]
+    fn test_environment() {
+        let mut world = World::default();
+        world.insert_resource(WorldTime { now: Instant::now(), dt: Duration::default() });
+        world.init_resource::<MovementTuning>();
+
+        let mut controller = MovementController::default();
+        controller.push_command(Swim);
+        controller.push_command(Fly);
+        let entity = world.spawn((
+            controller,
+            Transform::default(),
+            MobilityStat::new(0.01),
+            Environment::default(),
+            Stamina::new(100),
+        )).id();
+        world.spawn((
+            Transform { position: Point2::new(10.0, 0.0), ..Default::default() },
+            TriggerBody { shape: CollisionShape::Rectangle { w: 5.0, h: 5.0 } },
+            Region { medium: Medium::Water },
+        ));
+        world.spawn((
+            Transform { position: Point2::new(-10.0, 0.0), ..Default::default() },
+            TriggerBody { shape: CollisionShape::Rectangle { w: 5.0, h: 5.0 } },
+            Region { medium: Medium::Air },
+        ));
+
+        let tick = |world: &mut World, position: Point2<f32>| {
+            world.get_mut::<Transform>(entity).unwrap().position = position;
+            world.run_system_once(environment::update).unwrap();
+            world.run_system_once(update).unwrap();
+            world.get::<MovementController>(entity).unwrap().mode()
         };
-        movements.push(movement);
-    });
 
-    let protocol = GameServerProtocol {
-        protocol: Some(game_server_protocol::Protocol::MovementSync(MovementSync { movements }))
-    };
-    let buf = serialize_protocol(ProtocolCategory::Game, &protocol);
-    if let Err(e) = buf {
-        //TODO: Log
-        return;
+        // Rejected on the ground
+        assert!(tick(&mut world, Point2::new(0.0, 0.0)) == Standing);
+
+        // Entering and leaving water
+        assert!(tick(&mut world, Point2::new(10.0, 0.0)) == Swimming);
+        assert!(tick(&mut world, Point2::new(0.0, 0.0)) == Standing);
+
+        world.get_mut::<MovementController>(entity).unwrap().push_command(Fly);
+        assert!(tick(&mut world, Point2::new(-10.0, 0.0)) == Flying);
+
+        // Falls when exhausted
+        world.get_mut::<Stamina>(entity).unwrap().decrease(100);
+        assert!(tick(&mut world, Point2::new(-10.0, 1.0)) == Standing);
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut world = World::default();
+        world.init_resource::<ChunkGrid>();
+        world.init_resource::<SessionRegistry>();
+        world.init_resource::<MovementViolations>();
+        world.init_resource::<MovementTuning>();
+        world.init_resource::<WorldTime>();
+
+        // Allowed to drift 0.5 + 0.01 * (1.5 * 1.2) * 250 = 5.0
+        let mut controller = MovementController::default();
+        controller.push_command(Teleport { position: Point2::new(0.0, 3.0), forced: false });
+        controller.push_command(Teleport { position: Point2::new(10.0, 0.0), forced: false });
+        controller.push_command(Teleport { position: Point2::new(3.0, 0.0), forced: false });
+        controller.push_command(Teleport { position: Point2::new(3.0, 0.0), forced: true });
+        let entity = world.spawn((
+            controller,
+            Transform::default(),
+            MobilityStat::new(0.01),
+            KinematicBody { shape: CollisionShape::Circle { radius: 0.5 } },
+            Account { account_id: 7, privilege: Privilege::None },
+        )).id();
+        world.spawn((
+            Transform { position: Point2::new(2.0, 0.0), ..Default::default() },
+            StaticBody { shape: CollisionShape::Rectangle { w: 0.1, h: 2.0 } },
+        ));
+
+        world.run_system_once(chunk::update).unwrap();
+        world.run_system_once(validate).unwrap();
+
+        let commands = &world.get::<MovementController>(entity).unwrap().commands;
+        assert_eq!(commands.len(), 2);
+        assert!(matches!(commands[0], Teleport { forced: false, .. }));
+        assert!(matches!(commands[1], Teleport { forced: true, .. }));
+        assert_eq!(world.resource::<MovementViolations>().count(7), 2);
+    }
+
+    #[test]
+    fn test_validate_lag() {
+        let mut world = World::default();
+        world.init_resource::<ChunkGrid>();
+        world.init_resource::<SessionRegistry>();
+        world.init_resource::<MovementViolations>();
+        world.init_resource::<MovementTuning>();
+        world.init_resource::<WorldTime>();
+
+        // Delayed pongs don't widen the drift beyond 0.5 + 0.018 * (250 + 250) = 9.5
+        let mut controller = MovementController::default();
+        controller.push_command(Teleport { position: Point2::new(12.0, 0.0), forced: false });
+        world.spawn((
+            controller,
+            Transform::default(),
+            MobilityStat::new(0.01),
+            Account { account_id: 7, privilege: Privilege::None },
+            Latency { rtt: Duration::from_secs(5), jitter: Duration::ZERO },
+        ));
+
+        world.run_system_once(validate).unwrap();
+        assert_eq!(world.resource::<MovementViolations>().count(7), 1);
+    }
+
+    #[test]
+    fn test_validate_claims() {
+        let mut world = World::default();
+        world.init_resource::<ChunkGrid>();
+        world.init_resource::<SessionRegistry>();
+        world.init_resource::<MovementViolations>();
+        world.init_resource::<MovementTuning>();
+        world.init_resource::<WorldTime>();
+
+        // Each claim takes up 2.0 / 0.018 = 111ms of the 250ms allowance
+        let mut controller = MovementController::default();
+        controller.push_command(Teleport { position: Point2::new(2.0, 0.0), forced: false });
+        controller.push_command(Teleport { position: Point2::new(4.0, 0.0), forced: false });
+        controller.push_command(Teleport { position: Point2::new(6.0, 0.0), forced: false });
+        let entity = world.spawn((
+            controller,
+            Transform::default(),
+            MobilityStat::new(0.01),
+            Account { account_id: 7, privilege: Privilege::None },
+        )).id();
+
+        world.run_system_once(validate).unwrap();
+        let mut controller = world.get_mut::<MovementController>(entity).unwrap();
+        assert_eq!(controller.commands.len(), 2);
+        controller.commands.clear();
+        assert_eq!(world.resource::<MovementViolations>().count(7), 1);
+
+        // The allowance is refilled as time passes, up to 0.5 + 0.018 * (400 + 250) = 12.2
+        world.resource_mut::<WorldTime>().now += Duration::from_secs(1);
+        let mut controller = world.get_mut::<MovementController>(entity).unwrap();
+        controller.push_command(Teleport { position: Point2::new(12.0, 0.0), forced: false });
+        world.run_system_once(validate).unwrap();
+        assert_eq!(world.get::<MovementController>(entity).unwrap().commands.len(), 1);
+        assert_eq!(world.resource::<MovementViolations>().count(7), 1);
+
+        // Idling doesn't bank any more than that
+        world.resource_mut::<WorldTime>().now += Duration::from_secs(10);
+        let mut controller = world.get_mut::<MovementController>(entity).unwrap();
+        controller.commands.clear();
+        controller.push_command(Teleport { position: Point2::new(30.0, 0.0), forced: false });
+        world.run_system_once(validate).unwrap();
+        assert!(world.get::<MovementController>(entity).unwrap().commands.is_empty());
+        assert_eq!(world.resource::<MovementViolations>().count(7), 2);
+    }
+
+    #[test]
+    fn test_validate_walls() {
+        let mut world = World::default();
+        world.init_resource::<ChunkGrid>();
+        world.init_resource::<SessionRegistry>();
+        world.init_resource::<MovementViolations>();
+        world.init_resource::<MovementTuning>();
+        world.init_resource::<WorldTime>();
+
+        // Every claim is within the drift of 5.0, but only the last one is clear of the wall
+        let mut controller = MovementController::default();
+        controller.push_command(Teleport { position: Point2::new(3.0, 0.0), forced: false });
+        controller.push_command(Teleport { position: Point2::new(1.6, 0.0), forced: false });
+        controller.push_command(Teleport { position: Point2::new(1.2, 0.0), forced: false });
+        let entity = world.spawn((
+            controller,
+            Transform::default(),
+            MobilityStat::new(0.01),
+            KinematicBody { shape: CollisionShape::Circle { radius: 0.5 } },
+            Account { account_id: 7, privilege: Privilege::None },
+        )).id();
+        world.spawn((
+            Transform { position: Point2::new(2.0, 0.0), ..Default::default() },
+            StaticBody { shape: CollisionShape::Rectangle { w: 0.1, h: 2.0 } },
+        ));
+
+        world.run_system_once(chunk::update).unwrap();
+        world.run_system_once(validate).unwrap();
+
+        let commands = &world.get::<MovementController>(entity).unwrap().commands;
+        assert_eq!(commands.len(), 1);
+        assert!(matches!(commands[0], Teleport { position, .. } if position == Point2::new(1.2, 0.0)));
+        assert_eq!(world.resource::<MovementViolations>().count(7), 2);
     }
 
-    //TODO: How to broadcast?
+    #[test]
+    fn test_reset_claim() {
+        let mut world = World::default();
+        world.init_resource::<MovementTuning>();
+        world.insert_resource(WorldTime { dt: Duration::from_millis(50), ..Default::default() });
+
+        let claim = Some((Point2::origin(), world.resource::<WorldTime>().now));
+        let mut controller = MovementController { claim, ..Default::default() };
+        controller.push_command(Run { direction: Vector2::x_axis() });
+        let entity = world.spawn((controller, Transform::default(), MobilityStat::new(0.01))).id();
+
+        world.run_system_once(update).unwrap();
+
+        let position = world.get::<Transform>(entity).unwrap().position;
+        assert!(position.x > 0.0);
+        assert_eq!(world.get::<MovementController>(entity).unwrap().claim.unwrap().0, position);
+    }
 }
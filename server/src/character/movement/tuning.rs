@@ -0,0 +1,234 @@
+use bevy_ecs::prelude::*;
+use crate::character::Race;
+use crate::character::movement::{MovementMode, MovementState};
+use crate::world::time::WorldTime;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+pub const TUNING_FILE: &str = "movement.json";
+
+/// How often the tuning file is checked for modification.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Speed multipliers of the movement states and modes.
+#[derive(Debug, Deserialize, PartialEq, Copy, Clone)]
+#[serde(default)]
+pub struct MovementMultipliers {
+    pub walking: f32,
+    pub running: f32,
+    pub standing: f32,
+    pub crouching: f32,
+    pub crawling: f32,
+    pub swimming: f32,
+    pub flying: f32,
+}
+
+impl MovementMultipliers {
+    pub fn state(&self, state: MovementState) -> f32 {
+        match state {
+            MovementState::Walking => self.walking,
+            MovementState::Running => self.running,
+            _ => 1.0,
+        }
+    }
+
+    pub fn mode(&self, mode: MovementMode) -> f32 {
+        match mode {
+            MovementMode::Standing => self.standing,
+            MovementMode::Crouching => self.crouching,
+            MovementMode::Crawling => self.crawling,
+            MovementMode::Swimming => self.swimming,
+            MovementMode::Flying => self.flying,
+        }
+    }
+
+    /// The highest multiplier reachable by any combination of state and mode.
+    pub fn fastest(&self) -> f32 {
+        let state = self.walking.max(self.running);
+        let mode = [self.standing, self.crouching, self.crawling, self.swimming, self.flying]
+            .into_iter()
+            .fold(0.0, f32::max);
+        state * mode
+    }
+}
+
+impl Default for MovementMultipliers {
+    fn default() -> Self {
+        MovementMultipliers {
+            walking: 1.0,
+            running: 1.5,
+            standing: 1.0,
+            crouching: 0.6,
+            crawling: 0.3,
+            swimming: 0.5,
+            flying: 1.2,
+        }
+    }
+}
+
+/// Multipliers of a race, overriding the default ones.
+#[derive(Deserialize, Default)]
+struct RaceMultipliers {
+    walking: Option<f32>,
+    running: Option<f32>,
+    standing: Option<f32>,
+    crouching: Option<f32>,
+    crawling: Option<f32>,
+    swimming: Option<f32>,
+    flying: Option<f32>,
+}
+
+impl RaceMultipliers {
+    fn apply(&self, base: MovementMultipliers) -> MovementMultipliers {
+        MovementMultipliers {
+            walking: self.walking.unwrap_or(base.walking),
+            running: self.running.unwrap_or(base.running),
+            standing: self.standing.unwrap_or(base.standing),
+            crouching: self.crouching.unwrap_or(base.crouching),
+            crawling: self.crawling.unwrap_or(base.crawling),
+            swimming: self.swimming.unwrap_or(base.swimming),
+            flying: self.flying.unwrap_or(base.flying),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct TuningTable {
+    default: MovementMultipliers,
+    races: HashMap<Race, RaceMultipliers>,
+}
+
+struct TuningSource {
+    path: PathBuf,
+    // `None` while the file doesn't exist
+    modified: Option<SystemTime>,
+    checked: Option<Instant>,
+}
+
+/// Movement tuning loaded from the data directory. The file is reloaded when it's created or modified,
+/// keeping the previous values if the new ones can't be read.
+#[derive(Resource, Default)]
+pub struct MovementTuning {
+    default: MovementMultipliers,
+    races: HashMap<Race, MovementMultipliers>,
+    source: Option<TuningSource>,
+}
+
+impl MovementTuning {
+    /// Falls back to the defaults if the file can't be read, still watching it to be created or fixed.
+    pub fn load(path: &Path) -> Self {
+        let modified = fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+        let mut tuning = MovementTuning::read(path).unwrap_or_else(|e| {
+            eprintln!("Failed to load movement tuning from {}, using the defaults: {}", path.display(), e);
+            MovementTuning::default()
+        });
+        tuning.source = Some(TuningSource { path: path.to_path_buf(), modified, checked: None });
+
+        tuning
+    }
+
+    pub fn parse(json: &str) -> Result<Self, serde_json::Error> {
+        let table: TuningTable = serde_json::from_str(json)?;
+        let races = table.races.iter()
+            .map(|(race, multipliers)| (*race, multipliers.apply(table.default)))
+            .collect();
+
+        Ok(MovementTuning { default: table.default, races, source: None })
+    }
+
+    /// Multipliers of the race, or the default ones for characters without a race.
+    pub fn multipliers(&self, race: Option<Race>) -> &MovementMultipliers {
+        race.and_then(|race| self.races.get(&race)).unwrap_or(&self.default)
+    }
+
+    fn read(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Ok(MovementTuning::parse(&fs::read_to_string(path)?)?)
+    }
+}
+
+pub fn reload(mut tuning: ResMut<MovementTuning>, time: Res<WorldTime>) {
+    let tuning = tuning.bypass_change_detection();
+    let Some(source) = tuning.source.as_mut() else {
+        return;
+    };
+    if source.checked.is_some_and(|checked| time.now < checked + RELOAD_INTERVAL) {
+        return;
+    }
+    source.checked = Some(time.now);
+
+    let modified = match fs::metadata(&source.path).and_then(|metadata| metadata.modified()) {
+        Ok(modified) => Some(modified),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => {
+            eprintln!("Failed to check movement tuning {}: {}", source.path.display(), e);
+            return;
+        }
+    };
+    if modified == source.modified {
+        return;
+    }
+
+    // Broken files are not retried until modified again, and removed ones keep the values
+    source.modified = modified;
+    if modified.is_none() {
+        return;
+    }
+    match MovementTuning::read(&source.path) {
+        Ok(reloaded) => {
+            println!("Reloaded movement tuning from {}", source.path.display());
+            tuning.default = reloaded.default;
+            tuning.races = reloaded.races;
+        }
+        Err(e) => eprintln!("Failed to reload movement tuning from {}: {}", source.path.display(), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy_ecs::system::RunSystemOnce;
+
+    #[test]
+    fn test_tuning() {
+        let tuning = MovementTuning::parse(r#"{
+            "default": { "running": 2.0 },
+            "races": { "Elf": { "walking": 1.2 } }
+        }"#).unwrap();
+
+        let default = tuning.multipliers(None);
+        assert_eq!(default.state(MovementState::Running), 2.0);
+        assert_eq!(default.mode(MovementMode::Crouching), MovementMultipliers::default().crouching);
+        assert_eq!(tuning.multipliers(Some(Race::Human)), default);
+
+        // Races override the default of the file
+        let elf = tuning.multipliers(Some(Race::Elf));
+        assert_eq!(elf.state(MovementState::Walking), 1.2);
+        assert_eq!(elf.state(MovementState::Running), 2.0);
+    }
+
+    #[test]
+    fn test_reload_created() {
+        let path = std::env::temp_dir().join(format!("spire-tuning-{}.json", std::process::id()));
+        _ = fs::remove_file(&path);
+
+        let mut world = World::default();
+        world.insert_resource(MovementTuning::load(&path));
+        world.init_resource::<WorldTime>();
+        world.run_system_once(reload).unwrap();
+        assert_eq!(world.resource::<MovementTuning>().multipliers(None), &MovementMultipliers::default());
+
+        fs::write(&path, r#"{ "default": { "running": 2.0 } }"#).unwrap();
+        world.resource_mut::<WorldTime>().now += RELOAD_INTERVAL;
+        world.run_system_once(reload).unwrap();
+        _ = fs::remove_file(&path);
+
+        let default = world.resource::<MovementTuning>().multipliers(None);
+        assert_eq!(default.state(MovementState::Running), 2.0);
+    }
+}
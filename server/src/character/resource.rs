@@ -1,4 +1,102 @@
 use bevy_ecs::prelude::*;
+use crate::character::stat::CharacterStat;
+use crate::core::room_resource::SessionRegistry;
+use crate::protocol::*;
+use crate::protocol::game::*;
+use crate::world::interest::Interest;
+use crate::world::time::WorldTime;
+
+const BASE_HEALTH: u32 = 100;
+const HEALTH_PER_CONSTITUTION: u32 = 10;
+const BASE_MANA: u32 = 50;
+const MANA_PER_INTELLIGENCE: u32 = 5;
+const MAX_RAGE: u32 = 100;
+const BASE_STAMINA: u32 = 100;
+const STAMINA_PER_DEXTERITY: u32 = 5;
+
+pub fn max_health(stat: &CharacterStat) -> u32 {
+    BASE_HEALTH + stat.constitution() as u32 * HEALTH_PER_CONSTITUTION
+}
+
+pub fn max_mana(stat: &CharacterStat) -> u32 {
+    BASE_MANA + stat.intelligence() as u32 * MANA_PER_INTELLIGENCE
+}
+
+pub fn max_stamina(stat: &CharacterStat) -> u32 {
+    BASE_STAMINA + stat.dexterity() as u32 * STAMINA_PER_DEXTERITY
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ResourceKind {
+    Health,
+    Mana,
+    Rage,
+    Stamina,
+}
+
+// Health, Mana, Rage and Stamina share the same value handling, only their meaning differs.
+macro_rules! impl_resource {
+    ($resource:ident) => {
+        impl $resource {
+            pub fn value(&self) -> u32 {
+                self.value
+            }
+
+            pub fn max_value(&self) -> u32 {
+                self.max_value
+            }
+
+            /// The value is clamped to the new maximum.
+            pub fn set_max_value(&mut self, max_value: u32) {
+                self.max_value = max_value;
+                self.value = self.value.min(max_value);
+            }
+
+            pub fn ratio(&self) -> f32 {
+                if self.max_value == 0 {
+                    return 0.0;
+                }
+
+                self.value as f32 / self.max_value as f32
+            }
+
+            pub fn is_depleted(&self) -> bool {
+                self.value == 0
+            }
+
+            pub fn is_full(&self) -> bool {
+                self.value == self.max_value
+            }
+
+            /// Returns the actually increased amount.
+            pub fn increase(&mut self, amount: u32) -> u32 {
+                let increased = amount.min(self.max_value - self.value);
+                self.value += increased;
+                increased
+            }
+
+            /// Returns the actually decreased amount.
+            pub fn decrease(&mut self, amount: u32) -> u32 {
+                let decreased = amount.min(self.value);
+                self.value -= decreased;
+                decreased
+            }
+
+            /// Applies whole points of a regeneration tick, negative points drain.
+            pub fn regenerate(&mut self, points: i64) {
+                if points >= 0 {
+                    self.increase(points.min(u32::MAX as i64) as u32);
+                } else {
+                    self.decrease((-points).min(u32::MAX as i64) as u32);
+                }
+            }
+
+            fn is_regenerating(&self, points: i64) -> bool {
+                (points > 0 && !self.is_full()) || (points < 0 && !self.is_depleted())
+            }
+        }
+    };
+}
 
 #[derive(Component)]
 pub struct Health {
@@ -6,14 +104,331 @@ pub struct Health {
     max_value: u32,
 }
 
+impl Health {
+    pub fn new(max_value: u32) -> Self {
+        Health { value: max_value, max_value }
+    }
+
+    /// Returns the actually dealt damage.
+    pub fn damage(&mut self, amount: u32) -> u32 {
+        self.decrease(amount)
+    }
+
+    /// Returns the actually healed amount.
+    pub fn heal(&mut self, amount: u32) -> u32 {
+        self.increase(amount)
+    }
+}
+
+impl_resource!(Health);
+
 #[derive(Component)]
 pub struct Mana {
     value: u32,
     max_value: u32,
 }
 
+impl Mana {
+    pub fn new(max_value: u32) -> Self {
+        Mana { value: max_value, max_value }
+    }
+
+    /// Spends only if there is enough mana.
+    pub fn spend(&mut self, amount: u32) -> bool {
+        if self.value < amount {
+            return false;
+        }
+
+        self.value -= amount;
+        true
+    }
+}
+
+impl_resource!(Mana);
+
+/// Rage starts empty, builds up in combat and decays over time.
 #[derive(Component)]
 pub struct Rage {
     value: u32,
     max_value: u32,
 }
+
+impl Rage {
+    pub fn new() -> Self {
+        Rage { value: 0, max_value: MAX_RAGE }
+    }
+
+    /// Spends only if there is enough rage.
+    pub fn spend(&mut self, amount: u32) -> bool {
+        if self.value < amount {
+            return false;
+        }
+
+        self.value -= amount;
+        true
+    }
+}
+
+impl Default for Rage {
+    fn default() -> Self {
+        Rage::new()
+    }
+}
+
+impl_resource!(Rage);
+
+/// Drained by the demanding movements, like swimming and flying.
+#[derive(Component)]
+pub struct Stamina {
+    value: u32,
+    max_value: u32,
+}
+
+impl Stamina {
+    pub fn new(max_value: u32) -> Self {
+        Stamina { value: max_value, max_value }
+    }
+}
+
+impl_resource!(Stamina);
+
+/// Change of the resources per second. Negative rates drain the resource.
+#[derive(Component)]
+pub struct Regeneration {
+    pub health: f32,
+    pub mana: f32,
+    pub rage: f32,
+    pub stamina: f32,
+    // Fractions of a point carried over to the next tick
+    remainders: [f32; 4],
+}
+
+impl Regeneration {
+    pub fn new(health: f32, mana: f32, rage: f32, stamina: f32) -> Self {
+        Regeneration { health, mana, rage, stamina, remainders: [0.0; 4] }
+    }
+
+    /// Whole points to apply on this tick.
+    fn accumulate(&mut self, kind: ResourceKind, dt: f32) -> i64 {
+        let (rate, remainder) = match kind {
+            ResourceKind::Health => (self.health, &mut self.remainders[0]),
+            ResourceKind::Mana => (self.mana, &mut self.remainders[1]),
+            ResourceKind::Rage => (self.rage, &mut self.remainders[2]),
+            ResourceKind::Stamina => (self.stamina, &mut self.remainders[3]),
+        };
+
+        *remainder += rate * dt;
+        let points = remainder.trunc();
+        *remainder -= points;
+        points as i64
+    }
+}
+
+impl Default for Regeneration {
+    fn default() -> Self {
+        Regeneration::new(1.0, 2.0, -2.0, 5.0)
+    }
+}
+
+/// Marks a character whose health reached zero. Removed once healed.
+#[derive(Component)]
+pub struct Dead;
+
+#[derive(Event)]
+pub struct Died {
+    pub entity: Entity,
+}
+
+pub fn update_max_values(
+    mut query: Query<(
+        &CharacterStat,
+        Option<&mut Health>,
+        Option<&mut Mana>,
+        Option<&mut Stamina>), Changed<CharacterStat>>,
+) {
+    query.iter_mut().for_each(|(stat, health, mana, stamina)| {
+        if let Some(mut health) = health {
+            health.set_max_value(max_health(stat));
+        }
+        if let Some(mut mana) = mana {
+            mana.set_max_value(max_mana(stat));
+        }
+        if let Some(mut stamina) = stamina {
+            stamina.set_max_value(max_stamina(stat));
+        }
+    });
+}
+
+pub fn regenerate(
+    time: Res<WorldTime>,
+    mut query: Query<(
+        &mut Regeneration,
+        Option<&mut Health>,
+        Option<&mut Mana>,
+        Option<&mut Rage>,
+        Option<&mut Stamina>), Without<Dead>>,
+) {
+    let dt = time.dt.as_secs_f32();
+    query.iter_mut().for_each(|(mut regeneration, health, mana, rage, stamina)| {
+        // Only touch the resources actually changing, so that the sync isn't flooded
+        if let Some(mut health) = health {
+            let points = regeneration.accumulate(ResourceKind::Health, dt);
+            if health.is_regenerating(points) {
+                health.regenerate(points);
+            }
+        }
+        if let Some(mut mana) = mana {
+            let points = regeneration.accumulate(ResourceKind::Mana, dt);
+            if mana.is_regenerating(points) {
+                mana.regenerate(points);
+            }
+        }
+        if let Some(mut rage) = rage {
+            let points = regeneration.accumulate(ResourceKind::Rage, dt);
+            if rage.is_regenerating(points) {
+                rage.regenerate(points);
+            }
+        }
+        if let Some(mut stamina) = stamina {
+            let points = regeneration.accumulate(ResourceKind::Stamina, dt);
+            if stamina.is_regenerating(points) {
+                stamina.regenerate(points);
+            }
+        }
+    });
+}
+
+pub fn update_death(
+    mut commands: Commands,
+    query: Query<(Entity, &Health, Has<Dead>), Changed<Health>>,
+    mut died: EventWriter<Died>,
+) {
+    query.iter().for_each(|(entity, health, dead)| {
+        match (health.is_depleted(), dead) {
+            (true, false) => {
+                commands.entity(entity).insert(Dead);
+                died.send(Died { entity });
+            }
+            (false, true) => {
+                commands.entity(entity).remove::<Dead>();
+            }
+            _ => {}
+        }
+    });
+}
+
+/// Health is synced to every observer interested in the character, the others only to its owner.
+pub fn sync(
+    healths: Query<Ref<Health>>,
+    manas: Query<Ref<Mana>>,
+    rages: Query<Ref<Rage>>,
+    staminas: Query<Ref<Stamina>>,
+    observers: Query<(Entity, &Interest)>,
+    sessions: Res<SessionRegistry>,
+) {
+    observers.iter().for_each(|(observer, interest)| {
+        if sessions.get(observer).is_none() {
+            return;
+        }
+
+        let mut resources: Vec<ResourceValue> = interest.relevant()
+            .filter_map(|target| healths.get(*target).ok().map(|health| (target, health)))
+            .filter(|(target, health)| health.is_changed() || interest.entered().contains(target))
+            .map(|(target, health)| to_resource_value(*target, ResourceKind::Health, health.value, health.max_value))
+            .collect();
+
+        if let Ok(mana) = manas.get(observer) {
+            if mana.is_changed() {
+                resources.push(to_resource_value(observer, ResourceKind::Mana, mana.value, mana.max_value));
+            }
+        }
+        if let Ok(rage) = rages.get(observer) {
+            if rage.is_changed() {
+                resources.push(to_resource_value(observer, ResourceKind::Rage, rage.value, rage.max_value));
+            }
+        }
+        if let Ok(stamina) = staminas.get(observer) {
+            if stamina.is_changed() {
+                resources.push(to_resource_value(observer, ResourceKind::Stamina, stamina.value, stamina.max_value));
+            }
+        }
+
+        if resources.is_empty() {
+            return;
+        }
+
+        let protocol = GameServerProtocol {
+            protocol: Some(game_server_protocol::Protocol::ResourceSync(ResourceSync { resources }))
+        };
+        match serialize_protocol(ProtocolCategory::Game, &protocol) {
+            Ok(buf) => sessions.send_sync(observer, buf),
+            Err(e) => eprintln!("Failed to serialize resource sync: {}", e),
+        }
+    });
+}
+
+fn to_resource_value(entity: Entity, kind: ResourceKind, value: u32, max_value: u32) -> ResourceValue {
+    ResourceValue {
+        entity: entity.to_bits(),
+        kind: kind as i32,
+        value,
+        max_value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy_ecs::event::EventRegistry;
+    use bevy_ecs::system::RunSystemOnce;
+    use std::time::Duration;
+
+    #[test]
+    fn test_resource_clamp() {
+        let mut health = Health::new(100);
+        assert_eq!(health.damage(30), 30);
+        assert_eq!(health.heal(50), 30);
+        assert!(health.is_full());
+
+        health.set_max_value(60);
+        assert_eq!(health.value(), 60);
+        assert_eq!(health.damage(100), 60);
+        assert!(health.is_depleted());
+
+        let mut mana = Mana::new(10);
+        assert!(!mana.spend(11));
+        assert!(mana.spend(10));
+        assert!(mana.is_depleted());
+    }
+
+    #[test]
+    fn test_regenerate_and_death() {
+        let mut world = World::default();
+        world.insert_resource(WorldTime { dt: Duration::from_millis(500), ..Default::default() });
+        EventRegistry::register_event::<Died>(&mut world);
+
+        let mut health = Health::new(10);
+        health.damage(5);
+        let entity = world.spawn((health, Rage::new(), Regeneration::new(3.0, 0.0, -2.0, 0.0))).id();
+
+        // 1.5 points per tick, the fraction is carried over
+        world.run_system_once(regenerate).unwrap();
+        assert_eq!(world.get::<Health>(entity).unwrap().value(), 6);
+        world.run_system_once(regenerate).unwrap();
+        assert_eq!(world.get::<Health>(entity).unwrap().value(), 8);
+        assert_eq!(world.get::<Rage>(entity).unwrap().value(), 0);
+
+        world.get_mut::<Health>(entity).unwrap().damage(100);
+        world.run_system_once(update_death).unwrap();
+        assert!(world.get::<Dead>(entity).is_some());
+        assert_eq!(world.resource::<Events<Died>>().len(), 1);
+
+        // The dead don't regenerate
+        world.run_system_once(regenerate).unwrap();
+        assert!(world.get::<Health>(entity).unwrap().is_depleted());
+
+        world.get_mut::<Health>(entity).unwrap().heal(1);
+        world.run_system_once(update_death).unwrap();
+        assert!(world.get::<Dead>(entity).is_none());
+    }
+}
@@ -1,7 +1,7 @@
 use bevy_ecs::prelude::*;
 use tokio_postgres::{Client, error::Error};
 
-#[derive(Component)]
+#[derive(Component, Default)]
 pub struct CharacterStat {
     // Level
     level: u16,
@@ -35,6 +35,12 @@ impl CharacterStat {
             faith: row.get::<_, Option<i16>>(6).map(|v| v as u16)
         })
     }
+
+    pub fn level(&self) -> u16 { self.level }
+    pub fn strength(&self) -> u16 { self.strength }
+    pub fn dexterity(&self) -> u16 { self.dexterity }
+    pub fn constitution(&self) -> u16 { self.constitution }
+    pub fn intelligence(&self) -> u16 { self.intelligence }
 }
 
 #[derive(Component)]
@@ -43,12 +49,28 @@ pub struct MobilityStat {
     base_speed: f32,
 }
 
+impl MobilityStat {
+    pub fn new(base_speed: f32) -> Self {
+        MobilityStat { speed: base_speed, base_speed }
+    }
+
+    pub fn base_speed(&self) -> f32 { self.base_speed }
+}
+
 #[derive(Component)]
 pub struct CombatStat {
     pub attack: u32,
     base_attack: u32,
 }
 
+impl CombatStat {
+    pub fn new(base_attack: u32) -> Self {
+        CombatStat { attack: base_attack, base_attack }
+    }
+
+    pub fn base_attack(&self) -> u32 { self.base_attack }
+}
+
 #[derive(Component)]
 pub struct CraftingStat {
 
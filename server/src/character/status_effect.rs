@@ -1,6 +1,11 @@
 use bevy_ecs::prelude::*;
-use std::time::Instant;
+use crate::character::stat::MobilityStat;
+use crate::world::time::WorldTime;
+use std::cmp::Ordering;
+use std::mem::discriminant;
+use std::time::{Duration, Instant, SystemTime};
 use macros::StatusEffect;
+use tokio_postgres::{Client, error::Error};
 
 #[derive(Debug, PartialEq)]
 pub enum StatusEffectKind {
@@ -10,22 +15,285 @@ pub enum StatusEffectKind {
     Curse,
 }
 
-#[derive(StatusEffect)]
+/// Stats derived from their base value by the effects.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ModifiedStat {
+    Speed,
+}
+
+/// How a temporary effect is applied when the same effect is already active.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Stacking {
+    /// Keeps a single instance, extending its expiry.
+    Refresh,
+    /// Keeps a single instance, replaced only by a stronger one. An equal one extends the expiry.
+    Strongest,
+    /// Keeps up to `max` instances, replacing the one expiring first beyond it.
+    Stack { max: usize },
+}
+
+/// Ids are persisted and sent to clients, never reuse or change them.
+#[derive(StatusEffect, Debug, PartialEq, Copy, Clone)]
 pub enum StatusEffect {
-    #[debuff] Stun,
-    #[debuff] Slow { modifier: u8 },
-    #[buff] Haste { modifier: u8 },
+    #[debuff(id = 1, duration_ms = 2000)] Stun,
+    #[debuff(id = 2, duration_ms = 5000, dispellable)] Slow { #[decreases(Speed)] modifier: u8 },
+    #[buff(id = 3, duration_ms = 10000, max_stacks = 3, dispellable, play_time)] Haste { #[increases(Speed)] modifier: u8 },
+}
+
+impl StatusEffect {
+    /// Effects with a modifier keep the strongest instance, the others are refreshed.
+    pub fn stacking(&self) -> Stacking {
+        match self.max_stacks() {
+            1 if self.modifier().is_some() => Stacking::Strongest,
+            1 => Stacking::Refresh,
+            max => Stacking::Stack { max },
+        }
+    }
+
+    /// Strength of the effect, compared by the `Strongest` stacking.
+    pub fn magnitude(&self) -> f32 {
+        self.modifier().map(|(_, value)| value.abs()).unwrap_or_default()
+    }
+}
+
+/// When a saved temporary effect expires.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum SavedExpiry {
+    /// Keeps elapsing while the character is offline.
+    WallClock(SystemTime),
+    /// Elapses only while the character is in the world.
+    PlayTime(Duration),
+}
+
+/// A status effect as persisted in the database. Permanent effects have no expiry.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct SavedStatusEffect {
+    pub effect: StatusEffect,
+    pub expiry: Option<SavedExpiry>,
+}
+
+impl SavedStatusEffect {
+    /// Replaces all the saved effects of the character.
+    pub async fn save_all(
+        character_id: u64,
+        saved: &[SavedStatusEffect],
+        client: &mut Client,
+    ) -> Result<(), Error> {
+        let transaction = client.transaction().await?;
+        transaction.execute(
+            "DELETE FROM character_status_effects WHERE character_id=$1",
+            &[&(character_id as i64)],
+        ).await?;
+
+        for saved in saved {
+            let (remaining_ms, expires_at) = match saved.expiry {
+                Some(SavedExpiry::PlayTime(remaining)) => (Some(remaining.as_millis() as i64), None),
+                Some(SavedExpiry::WallClock(expires_at)) => (None, Some(expires_at)),
+                None => (None, None),
+            };
+
+            transaction.execute(
+                "INSERT INTO character_status_effects \
+                (character_id, effect_id, effect_values, remaining_ms, expires_at) \
+                VALUES ($1, $2, $3, $4, $5)",
+                &[
+                    &(character_id as i64),
+                    &(saved.effect.id() as i16),
+                    &saved.effect.values(),
+                    &remaining_ms,
+                    &expires_at,
+                ],
+            ).await?;
+        }
+
+        transaction.commit().await
+    }
 }
 
-#[derive(Component)]
+#[derive(Component, Default)]
 pub struct StatusEffectController {
     pub temporary_effects: Vec<(StatusEffect, Instant)>,
     pub permanent_effects: Vec<StatusEffect>,
 }
 
+impl StatusEffectController {
+    pub async fn load(character_id: u64, client: &Client) -> Result<StatusEffectController, Error> {
+        let rows = client.query(
+            "SELECT effect_id, effect_values, remaining_ms, expires_at \
+            FROM character_status_effects WHERE character_id=$1",
+            &[&(character_id as i64)],
+        ).await?;
+
+        let saved = rows.iter().filter_map(|row| {
+            let id = row.get::<_, i16>(0) as u16;
+            let Some(effect) = StatusEffect::from_values(id, &row.get::<_, Vec<i64>>(1)) else {
+                eprintln!("Unknown status effect: character_id={}, id={}", character_id, id);
+                return None;
+            };
+
+            let expiry = match (row.get::<_, Option<i64>>(2), row.get::<_, Option<SystemTime>>(3)) {
+                (Some(remaining_ms), _) => Some(SavedExpiry::PlayTime(Duration::from_millis(remaining_ms as u64))),
+                (None, Some(expires_at)) => Some(SavedExpiry::WallClock(expires_at)),
+                (None, None) => None,
+            };
+            Some(SavedStatusEffect { effect, expiry })
+        });
+
+        Ok(StatusEffectController::restore(saved, Instant::now(), SystemTime::now()))
+    }
+
+    /// Effects already expired in wall-clock are dropped.
+    pub fn restore(
+        saved: impl IntoIterator<Item = SavedStatusEffect>,
+        now: Instant,
+        wall_now: SystemTime,
+    ) -> Self {
+        let mut controller = StatusEffectController::default();
+        for SavedStatusEffect { effect, expiry } in saved {
+            match expiry {
+                Some(SavedExpiry::PlayTime(remaining)) => {
+                    controller.temporary_effects.push((effect, now + remaining));
+                }
+                Some(SavedExpiry::WallClock(expires_at)) => {
+                    if let Ok(remaining) = expires_at.duration_since(wall_now) {
+                        controller.temporary_effects.push((effect, now + remaining));
+                    }
+                }
+                None => controller.permanent_effects.push(effect),
+            }
+        }
+
+        controller
+    }
+
+    pub fn snapshot(&self, now: Instant, wall_now: SystemTime) -> Vec<SavedStatusEffect> {
+        let temporary = self.temporary_effects.iter()
+            .filter(|(_, expiry)| *expiry > now)
+            .map(|(effect, expiry)| {
+                let remaining = *expiry - now;
+                let expiry = if effect.expires_in_play_time() {
+                    SavedExpiry::PlayTime(remaining)
+                } else {
+                    SavedExpiry::WallClock(wall_now + remaining)
+                };
+                SavedStatusEffect { effect: *effect, expiry: Some(expiry) }
+            });
+        let permanent = self.permanent_effects.iter()
+            .map(|effect| SavedStatusEffect { effect: *effect, expiry: None });
+
+        temporary.chain(permanent).collect()
+    }
+
+    /// Applies a temporary effect until `until`, following the stacking rule of the effect.
+    pub fn apply(&mut self, effect: StatusEffect, until: Instant) {
+        let variant = discriminant(&effect);
+        let index = self.temporary_effects.iter()
+            .position(|(active, _)| discriminant(active) == variant);
+
+        match (effect.stacking(), index) {
+            (Stacking::Refresh, Some(index)) => {
+                let (_, expiry) = &mut self.temporary_effects[index];
+                *expiry = (*expiry).max(until);
+                return;
+            }
+            (Stacking::Strongest, Some(index)) => {
+                let (active, expiry) = &mut self.temporary_effects[index];
+                match effect.magnitude().total_cmp(&active.magnitude()) {
+                    Ordering::Less => {}
+                    Ordering::Equal => *expiry = (*expiry).max(until),
+                    Ordering::Greater => {
+                        *active = effect;
+                        *expiry = until;
+                    }
+                }
+                return;
+            }
+            (Stacking::Stack { max }, Some(_)) => {
+                let stacked = self.temporary_effects.iter()
+                    .filter(|(active, _)| discriminant(active) == variant)
+                    .count();
+                if stacked >= max {
+                    let first = self.temporary_effects.iter()
+                        .enumerate()
+                        .filter(|(_, (active, _))| discriminant(active) == variant)
+                        .min_by_key(|(_, (_, expiry))| *expiry)
+                        .map(|(index, _)| index);
+                    if let Some(first) = first {
+                        self.temporary_effects.remove(first);
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        self.temporary_effects.push((effect, until));
+    }
+
+    pub fn apply_permanent(&mut self, effect: StatusEffect) {
+        self.permanent_effects.push(effect);
+    }
+
+    pub fn is_expiring(&self, now: Instant) -> bool {
+        self.temporary_effects.iter().any(|(_, expiry)| *expiry <= now)
+    }
+
+    /// Removes the expired effects, returning them.
+    pub fn expire(&mut self, now: Instant) -> Vec<StatusEffect> {
+        let mut expired = Vec::new();
+        self.temporary_effects.retain(|(effect, expiry)| {
+            if *expiry <= now {
+                expired.push(*effect);
+                return false;
+            }
+            true
+        });
+
+        expired
+    }
+
+    pub fn effects(&self) -> impl Iterator<Item = &StatusEffect> {
+        self.temporary_effects.iter()
+            .map(|(effect, _)| effect)
+            .chain(self.permanent_effects.iter())
+    }
+
+    pub fn is_stunned(&self) -> bool {
+        self.effects().any(|effect| matches!(effect, StatusEffect::Stun))
+    }
+
+    /// Modifiers are percentages, multiplied together.
+    pub fn multiplier(&self, stat: ModifiedStat) -> f32 {
+        self.effects()
+            .filter_map(|effect| effect.modifier())
+            .filter(|(modified, _)| *modified == stat)
+            .fold(1.0, |multiplier, (_, value)| multiplier * (1.0 + value / 100.0).max(0.0))
+    }
+}
+
+pub fn update(
+    mut query: Query<(&mut StatusEffectController, Option<&mut MobilityStat>)>,
+    time: Res<WorldTime>,
+) {
+    query.iter_mut().for_each(|(mut controller, mobility)| {
+        if controller.is_expiring(time.now) {
+            controller.expire(time.now);
+        }
+
+        // Derived stats are only recomputed when the effects changed
+        if !controller.is_changed() {
+            return;
+        }
+
+        if let Some(mut mobility) = mobility {
+            mobility.speed = mobility.base_speed() * controller.multiplier(ModifiedStat::Speed);
+        }
+    });
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use bevy_ecs::system::RunSystemOnce;
 
     #[test]
     fn test_status_effect() {
@@ -37,5 +305,102 @@ mod tests {
 
         let haste = StatusEffect::Haste { modifier: 4 };
         assert_eq!(haste.kind(), StatusEffectKind::Buff);
+
+        assert_eq!(stun.id(), 1);
+        assert_eq!(stun.default_duration(), Some(Duration::from_millis(2000)));
+        assert!(!stun.is_dispellable());
+        assert_eq!(stun.modifier(), None);
+        assert_eq!(slow.modifier(), Some((ModifiedStat::Speed, -7.0)));
+        assert_eq!(haste.max_stacks(), 3);
+        assert_eq!(haste.tick_interval(), None);
+        assert_eq!(haste.modifier(), Some((ModifiedStat::Speed, 4.0)));
+
+        assert_eq!(slow.values(), vec![7]);
+        assert_eq!(StatusEffect::from_values(slow.id(), &slow.values()), Some(slow));
+        assert_eq!(StatusEffect::from_values(stun.id(), &[]), Some(stun));
+        assert_eq!(StatusEffect::from_values(haste.id(), &[]), None);
+        // Out of range of the field
+        assert_eq!(StatusEffect::from_values(slow.id(), &[256]), None);
+        assert_eq!(StatusEffect::from_values(slow.id(), &[-1]), None);
+    }
+
+    #[test]
+    fn test_status_effect_stacking() {
+        let now = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut controller = StatusEffectController::default();
+
+        controller.apply(StatusEffect::Stun, now + second);
+        controller.apply(StatusEffect::Stun, now + second * 3);
+        controller.apply(StatusEffect::Stun, now + second * 2);
+        assert_eq!(controller.temporary_effects, vec![(StatusEffect::Stun, now + second * 3)]);
+
+        controller.apply(StatusEffect::Slow { modifier: 20 }, now + second);
+        controller.apply(StatusEffect::Slow { modifier: 10 }, now + second * 5);
+        assert_eq!(controller.temporary_effects[1], (StatusEffect::Slow { modifier: 20 }, now + second));
+        controller.apply(StatusEffect::Slow { modifier: 50 }, now + second);
+        assert_eq!(controller.temporary_effects[1], (StatusEffect::Slow { modifier: 50 }, now + second));
+
+        for i in 1..=4 {
+            controller.apply(StatusEffect::Haste { modifier: 10 }, now + second * i);
+        }
+        let hastes: Vec<_> = controller.temporary_effects.iter()
+            .filter(|(effect, _)| matches!(effect, StatusEffect::Haste { .. }))
+            .map(|(_, expiry)| *expiry)
+            .collect();
+        assert_eq!(hastes, vec![now + second * 2, now + second * 3, now + second * 4]);
+    }
+
+    #[test]
+    fn test_status_effect_update() {
+        let mut world = World::default();
+        let now = Instant::now();
+        world.insert_resource(WorldTime { now, dt: Duration::default() });
+
+        let mut controller = StatusEffectController::default();
+        controller.apply(StatusEffect::Stun, now + Duration::from_secs(1));
+        controller.apply(StatusEffect::Slow { modifier: 50 }, now + Duration::from_secs(2));
+        let entity = world.spawn((controller, MobilityStat::new(4.0))).id();
+
+        world.run_system_once(update).unwrap();
+        assert_eq!(world.get::<MobilityStat>(entity).unwrap().speed, 2.0);
+        assert!(world.get::<StatusEffectController>(entity).unwrap().is_stunned());
+
+        world.resource_mut::<WorldTime>().now = now + Duration::from_secs(1);
+        world.run_system_once(update).unwrap();
+        assert!(!world.get::<StatusEffectController>(entity).unwrap().is_stunned());
+
+        world.resource_mut::<WorldTime>().now = now + Duration::from_secs(2);
+        world.run_system_once(update).unwrap();
+        assert_eq!(world.get::<MobilityStat>(entity).unwrap().speed, 4.0);
+    }
+
+    #[test]
+    fn test_status_effect_restore() {
+        let now = Instant::now();
+        let wall_now = SystemTime::now();
+        let mut controller = StatusEffectController::default();
+        controller.apply(StatusEffect::Stun, now + Duration::from_secs(2));
+        controller.apply(StatusEffect::Haste { modifier: 10 }, now + Duration::from_secs(5));
+        controller.apply_permanent(StatusEffect::Slow { modifier: 30 });
+
+        let saved = controller.snapshot(now, wall_now);
+        assert_eq!(saved, vec![
+            SavedStatusEffect {
+                effect: StatusEffect::Stun,
+                expiry: Some(SavedExpiry::WallClock(wall_now + Duration::from_secs(2))),
+            },
+            SavedStatusEffect {
+                effect: StatusEffect::Haste { modifier: 10 },
+                expiry: Some(SavedExpiry::PlayTime(Duration::from_secs(5))),
+            },
+            SavedStatusEffect { effect: StatusEffect::Slow { modifier: 30 }, expiry: None },
+        ]);
+
+        // Logged in again an hour later
+        let later = now + Duration::from_secs(10);
+        let restored = StatusEffectController::restore(saved, later, wall_now + Duration::from_secs(3600));
+        assert_eq!(restored.temporary_effects, vec![(StatusEffect::Haste { modifier: 10 }, later + Duration::from_secs(5))]);
+        assert_eq!(restored.permanent_effects, vec![StatusEffect::Slow { modifier: 30 }]);
     }
 }
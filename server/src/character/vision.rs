@@ -1,23 +1,188 @@
 use bevy_ecs::prelude::*;
-use crate::physics::object::{KinematicBody, Transform};
-use nalgebra::Vector2;
+use crate::physics::collision::raycast_shape;
+use crate::physics::object::{KinematicBody, StaticBody, Transform};
+use crate::world::chunk::ChunkGrid;
+use nalgebra::{UnitVector2, Vector2};
 
 #[derive(Component)]
 pub struct Visibility {
     visible: bool,
 }
 
+impl Visibility {
+    pub fn new(visible: bool) -> Self {
+        Visibility { visible }
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    pub fn set_visible(&mut self, visible: bool) {
+        self.visible = visible;
+    }
+}
+
+/// Rays are relative to the facing direction of the eye, where +x is forward.
 #[derive(Component)]
 pub struct Vision {
     rays: Vec<Vector2<f32>>,
     sight: Vec<Entity>,
 }
 
+impl Vision {
+    pub fn new(rays: Vec<Vector2<f32>>) -> Self {
+        Vision { rays, sight: Vec::new() }
+    }
+
+    /// Evenly spread `count` rays of `range` over the `angle`(radians) centered on the front.
+    pub fn cone(range: f32, angle: f32, count: usize) -> Self {
+        let rays = (0..count)
+            .map(|i| {
+                let theta = if count > 1 {
+                    -angle / 2.0 + angle * (i as f32) / ((count - 1) as f32)
+                } else {
+                    0.0
+                };
+                Vector2::new(theta.cos(), theta.sin()) * range
+            })
+            .collect();
+
+        Vision::new(rays)
+    }
+
+    pub fn sight(&self) -> &[Entity] {
+        &self.sight
+    }
+
+    pub fn can_see(&self, entity: Entity) -> bool {
+        self.sight.contains(&entity)
+    }
+
+    fn range(&self) -> f32 {
+        self.rays.iter().map(|ray| ray.norm()).fold(0.0, f32::max)
+    }
+}
+
+#[derive(Event)]
+pub struct SightEntered {
+    pub eye: Entity,
+    pub target: Entity,
+}
+
+#[derive(Event)]
+pub struct SightLeft {
+    pub eye: Entity,
+    pub target: Entity,
+}
+
 pub fn update_sight(
-    mut eyes: Query<(&mut Vision, &Transform)>,
+    grid: Res<ChunkGrid>,
+    mut eyes: Query<(Entity, &mut Vision, &Transform)>,
     objects: Query<(&Transform, &KinematicBody, &Visibility)>,
+    occluders: Query<(&Transform, &StaticBody)>,
+    mut entered_events: EventWriter<SightEntered>,
+    mut left_events: EventWriter<SightLeft>,
 ) {
-    eyes.iter_mut().for_each(|eye| {
+    eyes.iter_mut().for_each(|(eye, mut vision, eye_transform)| {
+        let origin = eye_transform.position;
+        let candidates: Vec<Entity> = grid
+            .entities_around(&origin, vision.range())
+            .filter(|entity| **entity != eye)
+            .copied()
+            .collect();
+
+        let mut sight = Vec::new();
+        for ray in &vision.rays {
+            let ray = rotate(ray, &eye_transform.rotation);
+            let length = ray.norm();
 
+            // The nearest occluder limits how far the ray can see
+            let limit = candidates.iter()
+                .filter_map(|entity| occluders.get(*entity).ok())
+                .filter_map(|(transform, body)| raycast_shape(origin, ray, transform.position, &body.shape))
+                .map(|(t, _)| t * length)
+                .fold(length, f32::min);
+
+            for entity in &candidates {
+                let Ok((transform, body, visibility)) = objects.get(*entity) else {
+                    continue;
+                };
+                if !visibility.is_visible() || sight.contains(entity) {
+                    continue;
+                }
+
+                if let Some((t, _)) = raycast_shape(origin, ray, transform.position, &body.shape) {
+                    if t * length <= limit {
+                        sight.push(*entity);
+                    }
+                }
+            }
+        }
+
+        for target in sight.iter().filter(|target| !vision.sight.contains(target)) {
+            entered_events.send(SightEntered { eye, target: *target });
+        }
+        for target in vision.sight.iter().filter(|target| !sight.contains(target)) {
+            left_events.send(SightLeft { eye, target: *target });
+        }
+
+        vision.sight = sight;
     });
-}
\ No newline at end of file
+}
+
+fn rotate(v: &Vector2<f32>, rotation: &UnitVector2<f32>) -> Vector2<f32> {
+    Vector2::new(
+        v.x * rotation.x - v.y * rotation.y,
+        v.x * rotation.y + v.y * rotation.x,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::collision::CollisionShape;
+    use crate::world::chunk;
+    use bevy_ecs::event::EventRegistry;
+    use bevy_ecs::system::RunSystemOnce;
+    use nalgebra::Point2;
+
+    #[test]
+    fn test_update_sight() {
+        let mut world = World::default();
+        world.init_resource::<ChunkGrid>();
+        EventRegistry::register_event::<SightEntered>(&mut world);
+        EventRegistry::register_event::<SightLeft>(&mut world);
+
+        let eye = world.spawn((Transform::default(), Vision::cone(10.0, 0.5, 5))).id();
+        let front = world.spawn((
+            Transform { position: Point2::new(5.0, 0.0), ..Default::default() },
+            KinematicBody { shape: CollisionShape::Circle { radius: 1.0 } },
+            Visibility::new(true),
+        )).id();
+        let side = world.spawn((
+            Transform { position: Point2::new(0.0, 5.0), ..Default::default() },
+            KinematicBody { shape: CollisionShape::Circle { radius: 1.0 } },
+            Visibility::new(true),
+        )).id();
+        let wall = world.spawn((
+            Transform { position: Point2::new(3.0, 0.0), ..Default::default() },
+            StaticBody { shape: CollisionShape::Rectangle { w: 0.5, h: 0.1 } },
+        )).id();
+
+        world.run_system_once(chunk::update).unwrap();
+        world.run_system_once(update_sight).unwrap();
+
+        // The wall is too thin to block the whole cone
+        let vision = world.get::<Vision>(eye).unwrap();
+        assert_eq!(vision.sight(), &[front]);
+        assert!(!vision.can_see(side));
+        assert_eq!(world.resource::<Events<SightEntered>>().len(), 1);
+
+        world.get_mut::<StaticBody>(wall).unwrap().shape = CollisionShape::Rectangle { w: 0.5, h: 2.0 };
+        world.run_system_once(update_sight).unwrap();
+
+        assert!(world.get::<Vision>(eye).unwrap().sight().is_empty());
+        assert_eq!(world.resource::<Events<SightLeft>>().len(), 1);
+    }
+}
@@ -1,8 +1,9 @@
+use bevy_ecs::system::Resource;
 use jsonwebtoken::DecodingKey;
 use serde::Deserialize;
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::OnceLock;
 
@@ -49,6 +50,23 @@ impl DatabaseConfig {
     }
 }
 
+/// Directory of the game data, fixed at build time. The files in it are read at runtime,
+/// so that they can be reloaded.
+pub struct DataConfig {
+    pub dir: PathBuf,
+}
+
+impl DataConfig {
+    pub fn load() -> Self {
+        let dir = PathBuf::from(option_env!("SPIRE_DATA_DIR").unwrap_or("data"));
+
+        DataConfig {
+            dir
+        }
+    }
+}
+
+#[derive(Resource)]
 pub struct AuthConfig {
     pub key: DecodingKey
 }
@@ -67,6 +85,8 @@ impl AuthConfig {
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub cheat_enabled: bool,
+    pub heartbeat_interval_ms: u64,
+    pub max_missed_heartbeats: u32,
 }
 
 impl Config {
@@ -1,6 +1,11 @@
-use bevy_ecs::world::World;
+use bevy_ecs::event::{event_update_system, EventRegistry};
+use bevy_ecs::prelude::*;
+use crate::core::room_resource::{ServerHandle, SessionRegistry};
 use crate::core::server::ServerContext;
-use crate::core::session::{InMessage, OutMessage, SessionContext};
+use crate::core::config::config;
+use crate::core::session::{resume_session, run_session, HeartbeatOptions, InMessage, OutMessage, RetrievedSession};
+use crate::player::PlayerBundle;
+use crate::world::time::WorldTime;
 use std::sync::Arc;
 use tokio::net::TcpStream;
 use tokio::sync::{broadcast, mpsc};
@@ -8,6 +13,7 @@ use tokio::time;
 
 pub enum RoomMessage {
     SessionEnter(TcpStream),
+    PlayerEnter(Box<PlayerBundle>, RetrievedSession),
     Broadcast(OutMessage),
 }
 
@@ -37,8 +43,8 @@ pub enum RoomMessageHandleResult {
     Pass,
 }
 
-pub type InMessageHandler = fn(&InMessage) -> InMessageHandleResult;
-pub type RoomMessageHandler = fn(&RoomMessage) -> RoomMessageHandleResult;
+pub type InMessageHandler = fn(&InMessage, &Arc<ServerContext>, &mut World) -> InMessageHandleResult;
+pub type RoomMessageHandler = fn(&RoomMessage, &Arc<ServerContext>, &mut World) -> RoomMessageHandleResult;
 
 pub struct RoomBuilder {
     pub in_message_handlers: Vec<InMessageHandler>,
@@ -48,6 +54,9 @@ pub struct RoomBuilder {
     pub room_message_buffer_size: usize,
 
     pub update_interval: Option<time::Duration>,
+
+    pub world: World,
+    pub schedule: Schedule,
 }
 
 impl RoomBuilder {
@@ -60,6 +69,9 @@ impl RoomBuilder {
             room_message_buffer_size: 0,
 
             update_interval: None,
+
+            world: World::default(),
+            schedule: Schedule::default(),
         }
     }
 
@@ -87,6 +99,26 @@ impl RoomBuilder {
         self.update_interval = Some(interval);
         self
     }
+
+    pub fn add_systems<M>(mut self, systems: impl IntoSystemConfigs<M>) -> Self {
+        self.schedule.add_systems(systems);
+        self
+    }
+
+    pub fn insert_resource<R: Resource>(mut self, resource: R) -> Self {
+        self.world.insert_resource(resource);
+        self
+    }
+
+    pub fn init_resource<R: Resource + FromWorld>(mut self) -> Self {
+        self.world.init_resource::<R>();
+        self
+    }
+
+    pub fn add_event<E: Event>(mut self) -> Self {
+        EventRegistry::register_event::<E>(&mut self.world);
+        self
+    }
 }
 
 impl Default for RoomBuilder {
@@ -111,9 +143,6 @@ pub fn run_room(
     }
 
     let (room_message_tx, mut room_message_rx) = mpsc::channel(builder.room_message_buffer_size);
-    if builder.room_message_handlers.is_empty() {
-        panic!("RoomMessage handlers must not be empty!");
-    }
 
     let ctx = Arc::new(RoomContext::new(room_message_tx, in_message_tx));
     let ctx_return = ctx.clone();
@@ -122,7 +151,14 @@ pub fn run_room(
         let mut in_message_buffer = Vec::with_capacity(builder.in_message_buffer_size);
         let mut room_message_buffer = Vec::with_capacity(builder.room_message_buffer_size);
 
-        let mut world = World::default();
+        let mut world = builder.world;
+        let mut schedule = builder.schedule;
+        schedule.add_systems(event_update_system);
+        world.init_resource::<WorldTime>();
+        world.init_resource::<SessionRegistry>();
+        world.insert_resource(ServerHandle::new(server_ctx.clone()));
+        SessionRegistry::register_hooks(&mut world);
+
         let update_enabled = builder.update_interval.is_some();
         let mut update_timer = time::interval(
             if update_enabled {
@@ -145,6 +181,7 @@ pub fn run_room(
                             &builder.in_message_handlers,
                             &ctx,
                             &server_ctx,
+                            &mut world,
                         ).await;
                     }
                 },
@@ -155,15 +192,17 @@ pub fn run_room(
 
                     for room_message in room_message_buffer.drain(0..n) {
                         handle_room_message(
-                            &room_message,
+                            room_message,
                             &builder.room_message_handlers,
                             &ctx,
                             &server_ctx,
+                            &mut world,
+                            &shutdown_rx,
                         ).await;
                     }
                 },
-                _ = update_timer.tick(), if update_enabled => {
-                    update();
+                now = update_timer.tick(), if update_enabled => {
+                    update(&mut world, &mut schedule, now);
                 }
                 _ = shutdown_rx.recv() => break,
             }
@@ -178,10 +217,11 @@ async fn handle_in_message(
     handlers: &Vec<InMessageHandler>,
     ctx: &Arc<RoomContext>,
     server_ctx: &Arc<ServerContext>,
+    world: &mut World,
 ) {
     let mut handled = false;
     for handler in handlers {
-        match handler(&message) {
+        match handler(&message, server_ctx, world) {
             InMessageHandleResult::Break => {
                 handled = true;
                 break;
@@ -197,19 +237,90 @@ async fn handle_in_message(
     }
 
     if !handled {
-        todo!("Print unhandled message error");
+        let (session_ctx, category, _) = message;
+        eprintln!("Unhandled in message from {}: {:?}", session_ctx, category);
     }
 }
 
 async fn handle_room_message(
-    message: &RoomMessage,
+    message: RoomMessage,
     handlers: &Vec<RoomMessageHandler>,
     ctx: &Arc<RoomContext>,
     server_ctx: &Arc<ServerContext>,
+    world: &mut World,
+    shutdown_rx: &broadcast::Receiver<()>,
 ) {
+    // Custom handlers run first and may intercept the message before the default handling.
+    for handler in handlers {
+        match handler(&message, server_ctx, world) {
+            RoomMessageHandleResult::Break => return,
+            RoomMessageHandleResult::Continue => continue,
+            RoomMessageHandleResult::Pass => continue,
+        }
+    }
 
+    match message {
+        RoomMessage::SessionEnter(stream) =>
+            handle_session_enter(stream, ctx, server_ctx, world, shutdown_rx),
+
+        RoomMessage::PlayerEnter(player_bundle, session) =>
+            handle_player_enter(player_bundle, session, ctx, world, shutdown_rx),
+
+        RoomMessage::Broadcast(message) =>
+            handle_broadcast(message, world),
+    }
+}
+
+fn handle_session_enter(
+    stream: TcpStream,
+    ctx: &Arc<RoomContext>,
+    server_ctx: &Arc<ServerContext>,
+    world: &mut World,
+    shutdown_rx: &broadcast::Receiver<()>,
+) {
+    let heartbeat = HeartbeatOptions {
+        interval: time::Duration::from_millis(config().heartbeat_interval_ms),
+        max_missed: config().max_missed_heartbeats,
+    };
+    let session_ctx = run_session(
+        stream,
+        ctx.in_message_tx.clone(),
+        shutdown_rx.resubscribe(),
+        heartbeat,
+        server_ctx.clone(),
+    );
+    world.resource_mut::<SessionRegistry>().add(session_ctx);
+}
+
+fn handle_player_enter(
+    player_bundle: Box<PlayerBundle>,
+    session: RetrievedSession,
+    ctx: &Arc<RoomContext>,
+    world: &mut World,
+    shutdown_rx: &broadcast::Receiver<()>,
+) {
+    resume_session(session, ctx.in_message_tx.clone(), shutdown_rx.resubscribe());
+
+    // The session is bound to the spawned entity by the `SessionRegistry` hooks.
+    world.spawn(*player_bundle);
 }
 
-fn update() {
+fn handle_broadcast(message: OutMessage, world: &mut World) {
+    let mut sessions = world.resource_mut::<SessionRegistry>();
+    sessions.retain_open();
+
+    for session_ctx in sessions.iter() {
+        session_ctx.send(message.clone());
+    }
+}
+
+fn update(world: &mut World, schedule: &mut Schedule, now: time::Instant) {
+    let now = now.into_std();
+    {
+        let mut time = world.resource_mut::<WorldTime>();
+        time.dt = now.saturating_duration_since(time.now);
+        time.now = now;
+    }
 
+    schedule.run(world);
 }
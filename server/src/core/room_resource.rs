@@ -0,0 +1,112 @@
+use bevy_ecs::component::ComponentId;
+use bevy_ecs::prelude::*;
+use bevy_ecs::world::DeferredWorld;
+use crate::core::server::ServerContext;
+use crate::core::session::{OutMessage, Session, SessionContext};
+use crate::protocol::game::Movement;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Lets the systems reach the server, e.g. to hand over the players leaving the room.
+#[derive(Resource)]
+pub struct ServerHandle {
+    pub ctx: Arc<ServerContext>,
+}
+
+impl ServerHandle {
+    pub fn new(ctx: Arc<ServerContext>) -> Self {
+        ServerHandle { ctx }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct SessionRegistry {
+    // Sessions entered the room, but not bound to any entity yet
+    sessions: Vec<SessionContext>,
+    entities: HashMap<Entity, SessionContext>,
+}
+
+impl SessionRegistry {
+    pub fn add(&mut self, session_ctx: SessionContext) {
+        self.sessions.push(session_ctx);
+    }
+
+    pub fn bind(&mut self, entity: Entity, session_ctx: SessionContext) {
+        self.entities.insert(entity, session_ctx);
+    }
+
+    pub fn unbind(&mut self, entity: Entity) -> Option<SessionContext> {
+        self.entities.remove(&entity)
+    }
+
+    /// Forgets a session not bound to any entity, e.g. when it's handed over to another room.
+    pub fn remove(&mut self, session_ctx: &SessionContext) {
+        self.sessions.retain(|other| !other.is_same(session_ctx));
+    }
+
+    pub fn get(&self, entity: Entity) -> Option<&SessionContext> {
+        self.entities.get(&entity)
+    }
+
+    pub fn retain_open(&mut self) {
+        self.sessions.retain(|session_ctx| !session_ctx.is_closed());
+        self.entities.retain(|_, session_ctx| !session_ctx.is_closed());
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SessionContext> {
+        self.sessions.iter().chain(self.entities.values())
+    }
+
+    pub fn entities(&self) -> impl Iterator<Item = (&Entity, &SessionContext)> {
+        self.entities.iter()
+    }
+
+    pub fn send(&self, entity: Entity, message: OutMessage) {
+        if let Some(session_ctx) = self.entities.get(&entity) {
+            session_ctx.send(message);
+        }
+    }
+
+    /// Sends a message which may be dropped if the client can't keep up, e.g. a sync superseded by later ones.
+    pub fn send_sync(&self, entity: Entity, message: OutMessage) {
+        if let Some(session_ctx) = self.entities.get(&entity) {
+            session_ctx.send_sync(message);
+        }
+    }
+
+    pub fn send_movements(&self, entity: Entity, movements: Vec<Movement>) {
+        if let Some(session_ctx) = self.entities.get(&entity) {
+            session_ctx.send_movements(movements);
+        }
+    }
+
+    pub fn send_all(&self, message: OutMessage) {
+        self.send_filtered(message, |_| true);
+    }
+
+    pub fn send_filtered(&self, message: OutMessage, filter: impl Fn(Entity) -> bool) {
+        for (entity, session_ctx) in &self.entities {
+            if !filter(*entity) {
+                continue;
+            }
+
+            session_ctx.send(message.clone());
+        }
+    }
+
+    /// Keeps the registry in sync with `Session` components spawned into or removed from the world.
+    pub fn register_hooks(world: &mut World) {
+        world.register_component_hooks::<Session>()
+            .on_add(on_session_add)
+            .on_remove(on_session_remove);
+    }
+}
+
+fn on_session_add(mut world: DeferredWorld, entity: Entity, _: ComponentId) {
+    let session_ctx = world.get::<Session>(entity).unwrap().ctx.clone();
+    world.resource_mut::<SessionRegistry>().bind(entity, session_ctx);
+}
+
+fn on_session_remove(mut world: DeferredWorld, entity: Entity, _: ComponentId) {
+    world.resource_mut::<SessionRegistry>().unbind(entity);
+}
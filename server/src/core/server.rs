@@ -1,36 +1,90 @@
 use crate::auth::auth_room;
+use crate::character::status_effect::SavedStatusEffect;
 use crate::core::config::*;
 use crate::core::resource::Resource;
 use crate::core::room::{RoomContext, RoomMessage};
-use crate::core::session::{run_session, InMessage, OutMessage, SessionContext};
+use crate::core::session::{CloseReason, OutMessage, OutboundStats, RetrievedSession, Session, SessionContext};
 use crate::player::PlayerBundle;
 use crate::player::account::*;
+use crate::station::station_room;
 use std::collections::HashMap;
 use std::error::Error;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{broadcast, mpsc};
-use tokio::task::JoinSet;
+use tokio::task::{JoinHandle, JoinSet};
 
 pub enum ServerMessage {
     Broadcast(OutMessage),
-    SessionAuthenticated { session_ctx: SessionContext, account: Account, character_id: u64 },
-    SessionClosed(SessionContext),
-    RoomTransferBegin { player_bundle: PlayerBundle, target: u64 },
-    RoomTransferCommit { player_bundle: PlayerBundle, target: u64 },
+    SessionAuthenticated { session: RetrievedSession, account: Account, character_id: u64 },
+    SessionClosed { session_ctx: SessionContext, reason: CloseReason },
+    RoomTransferBegin { player_bundle: Box<PlayerBundle>, session: RetrievedSession, target: u64 },
+    RoomTransferCommit { player_bundle: Box<PlayerBundle>, target: u64 },
+    PlayerLeft { character_id: u64, status_effects: Vec<SavedStatusEffect> },
 }
 
 pub struct ServerContext {
     pub message_tx: mpsc::Sender<ServerMessage>,
+    pub metrics: ServerMetrics,
 }
 
 impl ServerContext {
     pub fn new(message_tx: mpsc::Sender<ServerMessage>) -> ServerContext {
-        ServerContext { message_tx }
+        ServerContext { message_tx, metrics: ServerMetrics::default() }
     }
 }
 
+#[derive(Default)]
+pub struct ServerMetrics {
+    closed_sessions: Mutex<HashMap<CloseReason, u64>>,
+    outbound: Mutex<OutboundMetrics>,
+}
+
+/// Outbound queues of the closed sessions, aggregated.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct OutboundMetrics {
+    pub peak_depth: usize,
+    pub dropped: u64,
+    pub coalesced: u64,
+}
+
+impl ServerMetrics {
+    pub fn record_closed_session(&self, reason: CloseReason) {
+        *self.closed_sessions.lock().unwrap().entry(reason).or_default() += 1;
+    }
+
+    pub fn closed_sessions(&self, reason: CloseReason) -> u64 {
+        self.closed_sessions.lock().unwrap().get(&reason).copied().unwrap_or_default()
+    }
+
+    pub fn record_outbound(&self, stats: OutboundStats) {
+        let mut outbound = self.outbound.lock().unwrap();
+        outbound.peak_depth = outbound.peak_depth.max(stats.peak_depth);
+        outbound.dropped += stats.dropped;
+        outbound.coalesced += stats.coalesced;
+    }
+
+    pub fn outbound(&self) -> OutboundMetrics {
+        *self.outbound.lock().unwrap()
+    }
+}
+
+/// An account logged in with a session, and the login waiting for it to leave.
+struct Login {
+    session_ctx: SessionContext,
+    character_id: u64,
+    // Whether the player has entered a room, to be saved when it leaves
+    entered: bool,
+    next: Option<PendingLogin>,
+}
+
+struct PendingLogin {
+    session: RetrievedSession,
+    account: Account,
+    character_id: u64,
+}
+
 pub struct ServerRunOptions {
     pub dry_run: bool,
 }
@@ -45,7 +99,7 @@ pub async fn run_server(options: ServerRunOptions) -> Result<(), Box<dyn Error>>
     let ctx_listen = ctx.clone();
     let ctx_handle = ctx.clone();
     let auth_room_ctx = auth_room::run(ctx.clone(), shutdown_tx.subscribe());
-    // let station_room_ctx = station_room::run(ctx.clone(), shutdown_tx.subscribe());
+    let station_room_ctx = station_room::run(ctx.clone(), shutdown_tx.subscribe());
     
     let resource = Arc::new(Resource::load().await);
     let resource_handle = resource.clone();
@@ -61,7 +115,10 @@ pub async fn run_server(options: ServerRunOptions) -> Result<(), Box<dyn Error>>
         listen(server_config.game_listen_port, ctx_listen, auth_room_ctx, shutdown_rx_listen).await;
     });
     tasks.spawn(async move {
-        handle(ctx_handle, resource_handle, message_rx, shutdown_rx_handle).await;
+        let mut rooms = HashMap::new();
+        rooms.insert(0, station_room_ctx);
+
+        handle(ctx_handle, resource_handle, rooms, message_rx, shutdown_rx_handle).await;
     });
 
     while let Some(_) = tasks.join_next().await {}
@@ -103,11 +160,13 @@ async fn listen(
 async fn handle(
     ctx: Arc<ServerContext>,
     resource: Arc<Resource>,
+    mut rooms: HashMap<u64, Arc<RoomContext>>,
     mut message_rx: mpsc::Receiver<ServerMessage>,
     mut shutdown_rx: broadcast::Receiver<()>,
 ) {
-    let mut rooms = HashMap::new();
     let mut message_buffer = Vec::with_capacity(64);
+    // Logins of the accounts, by account_id
+    let mut logins = HashMap::new();
 
     loop {
         tokio::select! {
@@ -117,7 +176,7 @@ async fn handle(
                 }
 
                 for message in message_buffer.drain(0..n) {
-                    handle_internal(message, &ctx, &resource, &mut rooms).await;
+                    handle_internal(message, &ctx, &resource, &mut rooms, &mut logins).await;
                 }
             },
             _ = shutdown_rx.recv() => break,
@@ -130,21 +189,25 @@ async fn handle_internal(
     ctx: &Arc<ServerContext>,
     resource: &Arc<Resource>,
     rooms: &mut HashMap<u64, Arc<RoomContext>>,
+    logins: &mut HashMap<u64, Login>,
 ) {
     match message {
         ServerMessage::Broadcast(message) =>
             handle_broadcast(rooms, message).await,
 
-        ServerMessage::SessionAuthenticated {session_ctx, account, character_id } =>
-            handle_session_authenticated(ctx, resource.clone(), session_ctx, account, character_id).await,
+        ServerMessage::SessionAuthenticated { session, account, character_id } =>
+            handle_session_authenticated(ctx, resource.clone(), logins, session, account, character_id).await,
 
-        ServerMessage::SessionClosed(session_ctx) =>
-            handle_session_closed(session_ctx).await,
+        ServerMessage::SessionClosed { session_ctx, reason } =>
+            handle_session_closed(ctx, resource.clone(), logins, session_ctx, reason).await,
 
-        ServerMessage::RoomTransferBegin { player_bundle, target} =>
-            handle_room_transfer_begin(&rooms, player_bundle, target).await,
+        ServerMessage::RoomTransferBegin { player_bundle, session, target } =>
+            handle_room_transfer_begin(&rooms, logins, player_bundle, session, target).await,
 
         ServerMessage::RoomTransferCommit { player_bundle, target} => {},
+
+        ServerMessage::PlayerLeft { character_id, status_effects } =>
+            handle_player_left(ctx, resource.clone(), logins, character_id, status_effects),
     }
 }
 
@@ -160,32 +223,66 @@ async fn handle_broadcast(
 async fn handle_session_authenticated(
     ctx: &Arc<ServerContext>,
     resource: Arc<Resource>,
-    session_ctx: Arc<SessionContext>,
+    logins: &mut HashMap<u64, Login>,
+    session: RetrievedSession,
     account: Account,
     character_id: u64,
 ) {
-    if !session_ctx.is_open().await {
-        return
+    let account_id = account.account_id;
+    let pending = PendingLogin { session, account, character_id };
+
+    // The latest login wins, once the previous one has left and its player is saved
+    if let Some(login) = logins.get_mut(&account_id) {
+        println!("Duplicate login: account_id={}, {}", account_id, login.session_ctx);
+        login.session_ctx.close(CloseReason::DuplicateLogin);
+        if let Some(replaced) = login.next.replace(pending) {
+            replaced.session.close(CloseReason::DuplicateLogin);
+        }
+        return;
     }
 
+    start_login(ctx, resource, logins, pending, None);
+}
+
+/// Loads the player of the login after the previous player of the account is saved.
+fn start_login(
+    ctx: &Arc<ServerContext>,
+    resource: Arc<Resource>,
+    logins: &mut HashMap<u64, Login>,
+    pending: PendingLogin,
+    previous_save: Option<JoinHandle<()>>,
+) {
+    let PendingLogin { session, account, character_id } = pending;
+    logins.insert(account.account_id, Login {
+        session_ctx: session.ctx().clone(),
+        character_id,
+        entered: false,
+        next: None,
+    });
+
     let server_ctx = ctx.clone();
 
+    // The session stays paused while the player is loaded
     tokio::spawn(async move {
-        let session = Session::new(session_ctx);
+        if let Some(previous_save) = previous_save {
+            _ = previous_save.await;
+        }
+
         let client = match resource.db_client().await {
             Ok(client) => client,
             Err(e) => {
                 eprintln!("Error getting DB client: {}", e);
-                //TODO: Disconnect?
+                session.close(CloseReason::InternalError);
                 return
             }
         };
 
-        let player_bundle = match PlayerBundle::load(account, character_id, session, &client).await {
+        let player_session = Session::new(session.ctx().clone());
+        let player_bundle = match PlayerBundle::load(account, character_id, player_session, &client).await {
             Ok(player_bundle) => player_bundle,
             Err(e) => {
                 eprintln!("Error getting player bundle: {}", e);
-                //TODO: Disconnect?
+                session.close(CloseReason::InternalError);
                 return
             }
         };
@@ -194,30 +291,95 @@ async fn handle_session_authenticated(
         let last_room = 0;
 
         _ = server_ctx.message_tx.send(
-            ServerMessage::RoomTransferBegin {player_bundle, target: last_room});
+            ServerMessage::RoomTransferBegin { player_bundle, session, target: last_room }).await;
     });
 }
 
-async fn handle_session_closed(session: Arc<SessionContext>) {
-    //TODO: Remove from the current room
+/// Ends the login, starting the one waiting for it if any.
+fn finish_login(
+    ctx: &Arc<ServerContext>,
+    resource: Arc<Resource>,
+    logins: &mut HashMap<u64, Login>,
+    account_id: u64,
+    save: Option<JoinHandle<()>>,
+) {
+    let Some(login) = logins.remove(&account_id) else {
+        return;
+    };
+
+    if let Some(next) = login.next {
+        start_login(ctx, resource, logins, next, save);
+    }
+}
+
+/// Rooms drop the closed sessions by themselves, the server only cleans up the logins.
+async fn handle_session_closed(
+    ctx: &Arc<ServerContext>,
+    resource: Arc<Resource>,
+    logins: &mut HashMap<u64, Login>,
+    session_ctx: SessionContext,
+    reason: CloseReason,
+) {
+    ctx.metrics.record_closed_session(reason);
+    ctx.metrics.record_outbound(session_ctx.outbound_stats());
+
+    let Some((&account_id, login)) = logins.iter().find(|(_, login)| login.session_ctx.is_same(&session_ctx)) else {
+        return;
+    };
+
+    // Players in a room are finished when they have left
+    if !login.entered {
+        finish_login(ctx, resource, logins, account_id, None);
+    }
 }
 
 async fn handle_room_transfer_begin(
     rooms: &HashMap<u64, Arc<RoomContext>>,
+    logins: &mut HashMap<u64, Login>,
     player_bundle: Box<PlayerBundle>,
+    session: RetrievedSession,
     target: u64,
 ) {
-    if !player_bundle.session.ctx.is_open().await {
-        return
-    }
-
-    if !rooms.contains_key(&target) {
-        eprintln!("Invalid room transfer: {}, target={}", player_bundle.session.ctx, target);
+    let Some(room) = rooms.get(&target) else {
+        eprintln!("Invalid room transfer: {}, target={}", session.ctx(), target);
+        session.close(CloseReason::InternalError);
         return;
+    };
+
+    if let Some(login) = logins.values_mut().find(|login| login.session_ctx.is_same(session.ctx())) {
+        login.entered = true;
     }
 
-    {
-        let mut in_message_tx = player_bundle.session.ctx.in_message_tx.write().await;
-        *in_message_tx = rooms.get(&target).unwrap().in_message_tx.clone();
+    // The session resumes in the target room, delivering its in messages there
+    _ = room.message_tx.send(RoomMessage::PlayerEnter(player_bundle, session)).await;
+}
+
+fn handle_player_left(
+    ctx: &Arc<ServerContext>,
+    resource: Arc<Resource>,
+    logins: &mut HashMap<u64, Login>,
+    character_id: u64,
+    status_effects: Vec<SavedStatusEffect>,
+) {
+    let save_resource = resource.clone();
+    let save = tokio::spawn(async move {
+        let mut client = match save_resource.db_client().await {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("Error getting DB client: {}", e);
+                return
+            }
+        };
+
+        if let Err(e) = SavedStatusEffect::save_all(character_id, &status_effects, &mut client).await {
+            eprintln!("Error saving status effects: character_id={}, {}", character_id, e);
+        }
+    });
+
+    let account_id = logins.iter()
+        .find(|(_, login)| login.entered && login.character_id == character_id)
+        .map(|(&account_id, _)| account_id);
+    if let Some(account_id) = account_id {
+        finish_login(ctx, resource, logins, account_id, Some(save));
     }
 }
@@ -1,130 +1,1022 @@
+use bevy_ecs::prelude::*;
 use bytes::{Bytes, BytesMut};
-use crate::protocol::{HEADER_SIZE, ProtocolCategory, deserialize_header};
+use crate::core::server::{ServerContext, ServerMessage};
+use crate::protocol::*;
+use crate::protocol::game::{GameServerProtocol, Movement, MovementSync, game_server_protocol};
+use crate::protocol::net::{*, net_client_protocol, net_server_protocol};
 use std::error::Error;
+use std::fmt;
+use std::collections::VecDeque;
+use std::io;
 use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::{broadcast, mpsc, oneshot, Notify};
+use tokio::time;
+
+const MAX_FRAME_SIZE: usize = 8 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Frames waiting to be sent before the client is considered too slow.
+const OUT_QUEUE_CAPACITY: usize = 64;
+/// Time allowed to flush the pending messages and the disconnect message on close.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
 
 pub type InMessage = (SessionContext, ProtocolCategory, Bytes);
 pub type OutMessage = Bytes;
 
+/// Why a session is closed. Sent to the client on disconnect, unless the connection is lost.
+#[derive(Debug, Eq, PartialEq, Hash, Copy, Clone)]
+pub enum CloseReason {
+    BadToken = 1,
+    ServerShutdown = 2,
+    Kicked = 3,
+    DuplicateLogin = 4,
+    ProtocolError = 5,
+    Timeout = 6,
+    ConnectionLost = 7,
+    SlowClient = 8,
+    InternalError = 9,
+}
+
 #[derive(Clone)]
 pub struct SessionContext {
-    pub out_message_tx: mpsc::Sender<OutMessage>,
-    pub close_tx: mpsc::Sender<()>,
+    pub peer_addr: SocketAddr,
+    pub close_tx: mpsc::Sender<CloseReason>,
+    pub retrieve_tx: mpsc::Sender<oneshot::Sender<RetrievedSession>>,
+    heartbeat: Arc<Mutex<Heartbeat>>,
+    outbound: Arc<Outbound>,
+    end_reason: Arc<OnceLock<CloseReason>>,
+    authenticating: Arc<AtomicBool>,
 }
 
 impl SessionContext {
     pub fn new(
-        out_message_tx: mpsc::Sender<OutMessage>,
-        close_tx: mpsc::Sender<()>,
+        peer_addr: SocketAddr,
+        close_tx: mpsc::Sender<CloseReason>,
+        retrieve_tx: mpsc::Sender<oneshot::Sender<RetrievedSession>>,
     ) -> SessionContext {
         SessionContext {
-            out_message_tx,
+            peer_addr,
             close_tx,
+            retrieve_tx,
+            heartbeat: Arc::new(Mutex::new(Heartbeat::default())),
+            outbound: Arc::new(Outbound::default()),
+            end_reason: Arc::new(OnceLock::new()),
+            authenticating: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Queues a message which must be delivered. The session is closed if it can't keep up.
+    pub fn send(&self, message: OutMessage) {
+        self.enqueue(OutFrame::Reliable(message));
+    }
+
+    /// Queues a message which may be dropped, oldest first, when the client can't keep up.
+    pub fn send_sync(&self, message: OutMessage) {
+        let mut queue = self.outbound.queue.lock().unwrap();
+        if !queue.reserve() {
+            queue.stats.dropped += 1;
+            return;
+        }
+        queue.push(OutFrame::Sync(message));
+        drop(queue);
+
+        self.outbound.notify.notify_one();
+    }
+
+    /// Queues movements, merged into the last frame if it's a movement sync, the latest of an entity winning.
+    /// Frames queued after a movement sync keep it from being merged into, so that the order is kept.
+    pub fn send_movements(&self, movements: Vec<Movement>) {
+        let movements = {
+            let mut queue = self.outbound.queue.lock().unwrap();
+            match queue.coalesce(movements) {
+                Some(movements) => movements,
+                None => return,
+            }
+        };
+
+        self.enqueue(OutFrame::Movements(movements));
+    }
+
+    pub fn outbound_stats(&self) -> OutboundStats {
+        self.outbound.queue.lock().unwrap().stats
+    }
+
+    fn enqueue(&self, frame: OutFrame) {
+        let overflowed = {
+            let mut queue = self.outbound.queue.lock().unwrap();
+            let reserved = queue.reserve();
+            if reserved {
+                queue.push(frame);
+            }
+            !reserved
+        };
+
+        if overflowed {
+            eprintln!("{} is too slow, outbound queue is full", self);
+            self.close(CloseReason::SlowClient);
+            return;
         }
+        self.outbound.notify.notify_one();
     }
 
-    pub async fn close(&self) {
-        _ = self.close_tx.send(()).await;
+    /// Closes the session after telling the client the reason.
+    pub fn close(&self, reason: CloseReason) {
+        // A full channel means a close request is already pending.
+        _ = self.close_tx.try_send(reason);
     }
 
     pub fn is_closed(&self) -> bool {
         self.close_tx.is_closed()
     }
+
+    /// Why the session has ended, set before it's closed. `None` for sessions dropped while retrieved.
+    pub fn end_reason(&self) -> Option<CloseReason> {
+        self.end_reason.get().copied()
+    }
+
+    /// Marks the session as authenticating. Returns `false` if it already is, e.g. on a repeated login.
+    pub fn begin_authentication(&self) -> bool {
+        !self.authenticating.swap(true, Ordering::AcqRel)
+    }
+
+    /// Whether both contexts belong to the same session.
+    pub fn is_same(&self, other: &SessionContext) -> bool {
+        self.close_tx.same_channel(&other.close_tx)
+    }
+
+    /// Smoothed round trip time, `None` until the first heartbeat is answered.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.heartbeat.lock().unwrap().rtt
+    }
+
+    /// Mean deviation of the round trip times.
+    pub fn jitter(&self) -> Duration {
+        self.heartbeat.lock().unwrap().jitter
+    }
+
+    /// Pauses the session on a frame boundary and takes it out of its room, to be resumed in another.
+    /// Returns `None` if the session has ended.
+    pub async fn retrieve(&self) -> Option<RetrievedSession> {
+        let (tx, rx) = oneshot::channel();
+        self.retrieve_tx.send(tx).await.ok()?;
+        rx.await.ok()
+    }
+}
+
+impl fmt::Display for SessionContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Session({})", self.peer_addr)
+    }
+}
+
+#[derive(Component)]
+pub struct Session {
+    pub ctx: SessionContext,
+}
+
+impl Session {
+    pub fn new(ctx: SessionContext) -> Session {
+        Session { ctx }
+    }
+}
+
+/// Latency of the session, for the lag compensation.
+#[derive(Component, Default)]
+pub struct Latency {
+    pub rtt: Duration,
+    pub jitter: Duration,
+}
+
+pub fn update_latency(mut query: Query<(&Session, &mut Latency)>) {
+    query.iter_mut().for_each(|(session, mut latency)| {
+        let Some(rtt) = session.ctx.rtt() else {
+            return;
+        };
+        let jitter = session.ctx.jitter();
+        if latency.rtt != rtt || latency.jitter != jitter {
+            latency.rtt = rtt;
+            latency.jitter = jitter;
+        }
+    });
+}
+
+#[derive(Copy, Clone)]
+pub struct HeartbeatOptions {
+    pub interval: Duration,
+    /// Heartbeats left unanswered in a row before the session is closed.
+    pub max_missed: u32,
+}
+
+/// Ping/pong exchange of a session. Only the last ping is awaited, a ping sent before it's
+/// answered counts as missed.
+#[derive(Default)]
+struct Heartbeat {
+    sequence: u32,
+    sent_at: Option<Instant>,
+    missed: u32,
+    rtt: Option<Duration>,
+    jitter: Duration,
+}
+
+impl Heartbeat {
+    fn ping(&mut self, now: Instant) -> u32 {
+        if self.sent_at.is_some() {
+            self.missed += 1;
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+        self.sent_at = Some(now);
+        self.sequence
+    }
+
+    fn pong(&mut self, sequence: u32, now: Instant) {
+        if sequence != self.sequence {
+            return;
+        }
+        let Some(sent_at) = self.sent_at.take() else {
+            return;
+        };
+
+        self.missed = 0;
+        let sample = now.saturating_duration_since(sent_at);
+        // Smoothed as in TCP(RFC 6298) and RTP(RFC 3550)
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => {
+                let deviation = sample.abs_diff(rtt);
+                self.jitter = self.jitter * 15 / 16 + deviation / 16;
+                rtt * 7 / 8 + sample / 8
+            }
+            None => sample,
+        });
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct OutboundStats {
+    /// Frames waiting to be sent.
+    pub depth: usize,
+    pub peak_depth: usize,
+    pub dropped: u64,
+    pub coalesced: u64,
+}
+
+enum OutFrame {
+    Reliable(OutMessage),
+    Sync(OutMessage),
+    /// A movement sync, serialized when sent so that it can be merged into until then.
+    Movements(Vec<Movement>),
+}
+
+#[derive(Default)]
+struct OutQueue {
+    frames: VecDeque<OutFrame>,
+    stats: OutboundStats,
+}
+
+impl OutQueue {
+    /// Makes room for a frame, dropping the oldest sync frame if full.
+    fn reserve(&mut self) -> bool {
+        if self.frames.len() < OUT_QUEUE_CAPACITY {
+            return true;
+        }
+
+        match self.frames.iter().position(|frame| matches!(frame, OutFrame::Sync(_))) {
+            Some(index) => {
+                self.frames.remove(index);
+                self.stats.dropped += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn push(&mut self, frame: OutFrame) {
+        self.frames.push_back(frame);
+        self.stats.depth = self.frames.len();
+        self.stats.peak_depth = self.stats.peak_depth.max(self.stats.depth);
+    }
+
+    fn pop(&mut self) -> Option<OutMessage> {
+        loop {
+            let frame = self.frames.pop_front()?;
+            self.stats.depth = self.frames.len();
+
+            match frame {
+                OutFrame::Reliable(data) | OutFrame::Sync(data) => return Some(data),
+                OutFrame::Movements(movements) => {
+                    let protocol = GameServerProtocol {
+                        protocol: Some(game_server_protocol::Protocol::MovementSync(MovementSync { movements }))
+                    };
+                    match serialize_protocol(ProtocolCategory::Game, &protocol) {
+                        Ok(buf) => return Some(buf),
+                        Err(e) => eprintln!("Failed to serialize movement sync: {}", e),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Merges the movements into the last frame, or gives them back if it's not a movement sync.
+    fn coalesce(&mut self, movements: Vec<Movement>) -> Option<Vec<Movement>> {
+        let Some(OutFrame::Movements(pending_movements)) = self.frames.back_mut() else {
+            return Some(movements);
+        };
+
+        for movement in movements {
+            match pending_movements.iter_mut().find(|pending| pending.entity == movement.entity) {
+                Some(pending) => *pending = movement,
+                None => pending_movements.push(movement),
+            }
+        }
+        self.stats.coalesced += 1;
+        None
+    }
+}
+
+#[derive(Default)]
+struct Outbound {
+    queue: Mutex<OutQueue>,
+    notify: Notify,
+}
+
+/// A paused session. Frames not read yet stay in the socket and the messages not sent yet stay
+/// in the queue, until the session is resumed. Dropping it ends the session silently, so close it instead.
+pub struct RetrievedSession {
+    ctx: SessionContext,
+    reader: ReadHalf<TcpStream>,
+    writer: WriteHalf<TcpStream>,
+    close_rx: mpsc::Receiver<CloseReason>,
+    retrieve_rx: mpsc::Receiver<oneshot::Sender<RetrievedSession>>,
+    heartbeat: HeartbeatOptions,
+    server_ctx: Arc<ServerContext>,
+}
+
+impl RetrievedSession {
+    pub fn ctx(&self) -> &SessionContext {
+        &self.ctx
+    }
+
+    /// Ends the session without resuming it, telling the client the reason.
+    pub fn close(self, reason: CloseReason) {
+        let RetrievedSession { ctx, writer, close_rx, server_ctx, .. } = self;
+        tokio::spawn(end_session(ctx, Some(writer), close_rx, server_ctx, reason));
+    }
 }
 
-pub async fn run_session(
+pub fn run_session(
     stream: TcpStream,
     in_message_tx: mpsc::Sender<InMessage>,
-    mut shutdown_rx: broadcast::Receiver<()>,
-) {
+    shutdown_rx: broadcast::Receiver<()>,
+    heartbeat: HeartbeatOptions,
+    server_ctx: Arc<ServerContext>,
+) -> SessionContext {
     let peer_addr = stream
         .peer_addr()
         .unwrap_or(SocketAddr::from(([0, 0, 0, 0], 0)));
     let (reader, writer) = tokio::io::split(stream);
 
-    let (out_message_tx, out_message_rx) = mpsc::channel(32);
     let (close_tx, close_rx) = mpsc::channel(1);
-    let (retrieve_tx, retrieve_rx) = broadcast::channel(1);
-    let ctx = SessionContext::new(out_message_tx, close_tx);
+    let (retrieve_tx, retrieve_rx) = mpsc::channel(1);
+    let ctx = SessionContext::new(peer_addr, close_tx, retrieve_tx);
 
-    println!("Session({}) has started", peer_addr);
+    println!("{} has started", ctx);
 
-    let retrieve_rx_recv = retrieve_rx.resubscribe();
-    let retrieve_rx_send = retrieve_rx.resubscribe();
+    let session = RetrievedSession {
+        ctx: ctx.clone(),
+        reader,
+        writer,
+        close_rx,
+        retrieve_rx,
+        heartbeat,
+        server_ctx,
+    };
+    resume_session(session, in_message_tx, shutdown_rx);
+
+    ctx
+}
+
+/// Resumes a retrieved session, delivering its messages to `in_message_tx` from now on.
+pub fn resume_session(
+    session: RetrievedSession,
+    in_message_tx: mpsc::Sender<InMessage>,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) {
     tokio::spawn(async move {
-        let (recv_result, send_result) = tokio::join!(
-            recv(reader, in_message_tx, retrieve_rx_recv, ctx),
-            send(writer, out_message_rx, retrieve_rx_send),
-        );
+        let RetrievedSession {
+            ctx,
+            reader,
+            writer,
+            mut close_rx,
+            mut retrieve_rx,
+            heartbeat,
+            server_ctx,
+        } = session;
+        let mut heartbeat_timer = time::interval_at(time::Instant::now() + heartbeat.interval, heartbeat.interval);
 
-        // println!("Session({}) has ended", peer_addr);
+        // Both directions pause on the next frame boundary
+        let (pause_tx, _) = broadcast::channel(1);
+        let recv = recv(reader, in_message_tx, pause_tx.subscribe(), ctx.clone(), FrameLimits::default());
+        let send = send(writer, ctx.outbound.clone(), pause_tx.subscribe());
+        tokio::pin!(recv, send);
+
+        let mut paused_reader = None;
+        let mut paused_writer = None;
+        let mut retriever = None;
+        let reason = loop {
+            // The session ends as soon as either direction ends
+            tokio::select! {
+                recv_result = &mut recv, if paused_reader.is_none() => match recv_result {
+                    RecvResult::Retrieve(reader, _) => paused_reader = Some(reader),
+                    RecvResult::EOF => break CloseReason::ConnectionLost,
+                    RecvResult::Closed => break CloseReason::ServerShutdown,
+                    RecvResult::Error(e) => {
+                        eprintln!("{} failed to receive: {}", ctx, e);
+                        break e.close_reason();
+                    }
+                },
+                send_result = &mut send, if paused_writer.is_none() => match send_result {
+                    SendResult::Retrieve(writer) => paused_writer = Some(writer),
+                    SendResult::Error(e) => {
+                        eprintln!("{} failed to send: {}", ctx, e);
+                        break CloseReason::ConnectionLost;
+                    }
+                },
+                tx = retrieve_rx.recv(), if retriever.is_none() => {
+                    retriever = tx;
+                    _ = pause_tx.send(());
+                },
+                _ = heartbeat_timer.tick(), if retriever.is_none() => {
+                    if !send_ping(&ctx, heartbeat.max_missed) {
+                        break CloseReason::Timeout;
+                    }
+                },
+                Some(reason) = close_rx.recv() => break reason,
+                _ = shutdown_rx.recv() => break CloseReason::ServerShutdown,
+            }
+
+            if paused_reader.is_none() || paused_writer.is_none() {
+                continue;
+            }
+
+            let (Some(reader), Some(writer), Some(retriever)) =
+                (paused_reader.take(), paused_writer.take(), retriever.take()) else {
+                continue;
+            };
+            let session = RetrievedSession {
+                ctx: ctx.clone(),
+                reader,
+                writer,
+                close_rx,
+                retrieve_rx,
+                heartbeat,
+                server_ctx,
+            };
+            if retriever.send(session).is_err() {
+                eprintln!("{} was dropped while retrieved", ctx);
+            }
+            return;
+        };
+
+        // A lost connection can't be told, and its sending may have already ended
+        let writer = match paused_writer {
+            _ if reason == CloseReason::ConnectionLost => None,
+            Some(writer) => Some(writer),
+            None => {
+                _ = pause_tx.send(());
+                match time::timeout(CLOSE_TIMEOUT, &mut send).await {
+                    Ok(SendResult::Retrieve(writer)) => Some(writer),
+                    _ => None,
+                }
+            }
+        };
+        end_session(ctx, writer, close_rx, server_ctx, reason).await;
     });
 }
 
-enum RecvResult {
-    Retrieve(ReadHalf<TcpStream>, mpsc::Sender<InMessage>),
+/// Tells the client the reason if it can be reached, and reports the end to the server.
+async fn end_session(
+    ctx: SessionContext,
+    writer: Option<WriteHalf<TcpStream>>,
+    close_rx: mpsc::Receiver<CloseReason>,
+    server_ctx: Arc<ServerContext>,
+    reason: CloseReason,
+) {
+    if let Some(writer) = writer {
+        match time::timeout(CLOSE_TIMEOUT, disconnect(writer, &ctx.outbound, reason)).await {
+            Ok(Ok(())) => {},
+            Ok(Err(e)) => eprintln!("{} failed to disconnect: {}", ctx, e),
+            Err(e) => eprintln!("{} failed to disconnect: {}", ctx, e),
+        }
+    }
+
+    println!("{} has ended: {:?}", ctx, reason);
+
+    // Closed before the server hears of it, so that it's never mistaken for an open session.
+    // The room learns the reason from the context once it sees the session closed.
+    _ = ctx.end_reason.set(reason);
+    drop(close_rx);
+    _ = server_ctx.message_tx.send(ServerMessage::SessionClosed { session_ctx: ctx, reason }).await;
+}
+
+/// Flushes the pending messages followed by the disconnect message, and shuts the connection down.
+async fn disconnect<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    outbound: &Outbound,
+    reason: CloseReason,
+) -> io::Result<()> {
+    loop {
+        let Some(data) = outbound.queue.lock().unwrap().pop() else {
+            break;
+        };
+        writer.write_all(&data[..]).await?;
+    }
+
+    let protocol = NetServerProtocol {
+        protocol: Some(net_server_protocol::Protocol::Disconnect(Disconnect { reason: reason as i32 }))
+    };
+    let buf = serialize_protocol(ProtocolCategory::Net, &protocol)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    writer.write_all(&buf[..]).await?;
+    writer.shutdown().await
+}
+
+/// Returns false if too many heartbeats are missed, and the session should be closed.
+fn send_ping(ctx: &SessionContext, max_missed: u32) -> bool {
+    let (sequence, missed) = {
+        let mut heartbeat = ctx.heartbeat.lock().unwrap();
+        (heartbeat.ping(Instant::now()), heartbeat.missed)
+    };
+    if missed > max_missed {
+        eprintln!("{} missed {} heartbeats", ctx, missed);
+        return false;
+    }
+
+    let protocol = NetServerProtocol {
+        protocol: Some(net_server_protocol::Protocol::Ping(Ping { sequence }))
+    };
+    match serialize_protocol(ProtocolCategory::Net, &protocol) {
+        // Dropped if the client can't keep up, to be counted as missed
+        Ok(buf) => ctx.send_sync(buf),
+        Err(e) => eprintln!("Failed to serialize ping: {}", e),
+    }
+
+    true
+}
+
+/// Limits of the frames received from the peer.
+#[derive(Copy, Clone)]
+pub struct FrameLimits {
+    pub max_frame_size: usize,
+    /// Time allowed to receive the rest of a frame once it has begun.
+    pub read_timeout: Duration,
+    /// Time allowed between frames.
+    pub idle_timeout: Duration,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        FrameLimits {
+            max_frame_size: MAX_FRAME_SIZE,
+            read_timeout: READ_TIMEOUT,
+            idle_timeout: IDLE_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RecvError {
+    Io(io::Error),
+    /// The declared length of the frame exceeds the limit.
+    FrameTooLarge { length: usize, max: usize },
+    /// The peer closed the connection in the middle of a frame.
+    Truncated,
+    ReadTimeout,
+    IdleTimeout,
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecvError::Io(e) => write!(f, "{}", e),
+            RecvError::FrameTooLarge { length, max } => write!(f, "frame too large: length={}, max={}", length, max),
+            RecvError::Truncated => write!(f, "truncated frame"),
+            RecvError::ReadTimeout => write!(f, "read timed out"),
+            RecvError::IdleTimeout => write!(f, "idle timed out"),
+        }
+    }
+}
+
+impl Error for RecvError {}
+
+impl RecvError {
+    pub fn close_reason(&self) -> CloseReason {
+        match self {
+            RecvError::Io(_) | RecvError::Truncated => CloseReason::ConnectionLost,
+            RecvError::FrameTooLarge { .. } => CloseReason::ProtocolError,
+            RecvError::ReadTimeout | RecvError::IdleTimeout => CloseReason::Timeout,
+        }
+    }
+}
+
+impl From<io::Error> for RecvError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => RecvError::Truncated,
+            _ => RecvError::Io(e),
+        }
+    }
+}
+
+pub enum RecvResult<R> {
+    Retrieve(R, mpsc::Sender<InMessage>),
+    /// The peer closed the connection between frames.
     EOF,
-    Error(Box<dyn Error + Send + Sync>),
+    /// The room no longer receives messages.
+    Closed,
+    Error(RecvError),
 }
 
-async fn recv(
-    mut reader: ReadHalf<TcpStream>,
-    mut in_message_tx: mpsc::Sender<InMessage>,
+async fn recv<R: AsyncRead + Unpin>(
+    mut reader: R,
+    in_message_tx: mpsc::Sender<InMessage>,
     mut retrieve_rx: broadcast::Receiver<()>,
     ctx: SessionContext,
-) -> RecvResult {
+    limits: FrameLimits,
+) -> RecvResult<R> {
     loop {
+        // Waiting for the first byte is cancel safe, unlike reading the rest of the frame.
+        // A pending retrieval wins, so that no frame is read once the session is retrieved.
         let mut header_buf = [0u8; HEADER_SIZE];
-        match reader.read_exact(&mut header_buf).await {
-            Ok(n) if n == 0 => return RecvResult::EOF,
-            Ok(_) => {},
-            Err(e) => return RecvResult::Error(e.into()),
+        let first = tokio::select! {
+            biased;
+            _ = retrieved(&mut retrieve_rx) => None,
+            r = time::timeout(limits.idle_timeout, reader.read(&mut header_buf[..1])) => Some(r),
+        };
+        match first {
+            Some(Ok(Ok(0))) => return RecvResult::EOF,
+            Some(Ok(Ok(_))) => {},
+            Some(Ok(Err(e))) => return RecvResult::Error(e.into()),
+            Some(Err(_)) => return RecvResult::Error(RecvError::IdleTimeout),
+            None => return RecvResult::Retrieve(reader, in_message_tx),
         }
-        let header = deserialize_header(&header_buf);
 
-        let mut body_buf = BytesMut::with_capacity(header.length);
-        match reader.read_exact(&mut body_buf[..header.length]).await {
-            Ok(n) if n == 0 => return RecvResult::EOF,
-            Ok(_) => {},
-            Err(e) => return RecvResult::Error(e.into()),
-        }
+        let frame = time::timeout(limits.read_timeout, read_frame(&mut reader, &mut header_buf, limits.max_frame_size));
+        let (category, body) = match frame.await {
+            Ok(Ok(frame)) => frame,
+            Ok(Err(e)) => return RecvResult::Error(e),
+            Err(_) => return RecvResult::Error(RecvError::ReadTimeout),
+        };
 
-        _ = in_message_tx.send((ctx.clone(), header.category, body_buf.freeze())).await;
+        // Heartbeats are answered to the session itself, not to the room
+        if let Some(pong) = to_pong(category, &body) {
+            ctx.heartbeat.lock().unwrap().pong(pong.sequence, Instant::now());
+            continue;
+        }
 
-        if let Ok(_) = retrieve_rx.try_recv() {
-            return RecvResult::Retrieve(reader, in_message_tx);
+        if in_message_tx.send((ctx.clone(), category, body)).await.is_err() {
+            return RecvResult::Closed;
         }
     }
 }
 
-enum SendResult {
-    Retrieve(WriteHalf<TcpStream>, mpsc::Receiver<OutMessage>),
-    Error(Box<dyn Error + Send + Sync>),
-    Closed,
+fn to_pong(category: ProtocolCategory, body: &Bytes) -> Option<Pong> {
+    if category != ProtocolCategory::Net {
+        return None;
+    }
+
+    match NetClientProtocol::decode(body.clone()) {
+        Ok(NetClientProtocol { protocol: Some(net_client_protocol::Protocol::Pong(pong)) }) => Some(pong),
+        _ => None,
+    }
 }
 
-async fn send(
-    mut writer: WriteHalf<TcpStream>,
-    mut out_message_rx: mpsc::Receiver<OutMessage>,
+/// Reads the rest of a frame whose first byte is already in `header_buf`.
+async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    header_buf: &mut [u8; HEADER_SIZE],
+    max_frame_size: usize,
+) -> Result<(ProtocolCategory, Bytes), RecvError> {
+    reader.read_exact(&mut header_buf[1..]).await?;
+    let header = deserialize_header(header_buf);
+    if header.length > max_frame_size {
+        return Err(RecvError::FrameTooLarge { length: header.length, max: max_frame_size });
+    }
+
+    let mut body_buf = BytesMut::zeroed(header.length);
+    reader.read_exact(&mut body_buf).await?;
+
+    Ok((header.category, body_buf.freeze()))
+}
+
+pub enum SendResult<W> {
+    Retrieve(W),
+    Error(io::Error),
+}
+
+async fn send<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    outbound: Arc<Outbound>,
     mut retrieve_rx: broadcast::Receiver<()>,
-) -> SendResult {
+) -> SendResult<W> {
     loop {
-        tokio::select! {
-            m = out_message_rx.recv() => match m {
-                Some(data) => {
-                    if let Err(e) = writer.write_all(&data[..]).await {
-                        return SendResult::Error(e.into());
-                    }
-                }
-                None => return SendResult::Closed,
-            },
-            r = retrieve_rx.recv() => return match r {
-                Ok(_) => SendResult::Retrieve(writer, out_message_rx),
-                Err(_) => SendResult::Closed,
+        // Pending frames stay in the queue of the context while retrieved
+        let next = outbound.queue.lock().unwrap().pop();
+        let Some(data) = next else {
+            tokio::select! {
+                _ = outbound.notify.notified() => continue,
+                _ = retrieved(&mut retrieve_rx) => return SendResult::Retrieve(writer),
             }
+        };
+
+        if let Err(e) = writer.write_all(&data[..]).await {
+            return SendResult::Error(e);
         }
+        if retrieve_rx.try_recv().is_ok() {
+            return SendResult::Retrieve(writer);
+        }
+    }
+}
+
+/// Resolves when the session is retrieved. Never resolves once retrieval is no longer possible.
+async fn retrieved(retrieve_rx: &mut broadcast::Receiver<()>) {
+    if retrieve_rx.recv().await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::serialize_protocol;
+    use crate::protocol::game::{GameClientProtocol, Halt, game_client_protocol};
+    use tokio::io::{duplex, DuplexStream};
+
+    const HEARTBEAT: HeartbeatOptions = HeartbeatOptions { interval: Duration::from_secs(60), max_missed: 0 };
+
+    const LIMITS: FrameLimits = FrameLimits {
+        max_frame_size: 2,
+        read_timeout: Duration::from_millis(50),
+        idle_timeout: Duration::from_millis(200),
+    };
+
+    fn empty_frame() -> Bytes {
+        serialize_protocol(ProtocolCategory::Game, &GameClientProtocol { protocol: None }).unwrap()
+    }
+
+    fn halt_frame() -> Bytes {
+        let protocol = GameClientProtocol {
+            protocol: Some(game_client_protocol::Protocol::Halt(Halt { entity: 1 }))
+        };
+        serialize_protocol(ProtocolCategory::Game, &protocol).unwrap()
+    }
+
+    async fn connect() -> (TcpStream, TcpStream) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        (peer, stream)
+    }
+
+    fn start(reader: DuplexStream, limits: FrameLimits) -> (
+        tokio::task::JoinHandle<RecvResult<DuplexStream>>,
+        mpsc::Receiver<InMessage>,
+        broadcast::Sender<()>,
+    ) {
+        let (in_message_tx, in_message_rx) = mpsc::channel(8);
+        let (close_tx, _) = mpsc::channel(1);
+        let (session_retrieve_tx, _) = mpsc::channel(1);
+        let (retrieve_tx, retrieve_rx) = broadcast::channel(1);
+        let ctx = SessionContext::new(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            close_tx,
+            session_retrieve_tx,
+        );
+
+        let handle = tokio::spawn(recv(reader, in_message_tx, retrieve_rx, ctx, limits));
+        (handle, in_message_rx, retrieve_tx)
+    }
+
+    #[tokio::test]
+    async fn test_recv_frames() {
+        let (mut peer, reader) = duplex(64);
+        let limits = FrameLimits { max_frame_size: MAX_FRAME_SIZE, ..LIMITS };
+        let (handle, mut in_message_rx, _retrieve_tx) = start(reader, limits);
+
+        // Heartbeats are not delivered to the room
+        let pong = NetClientProtocol {
+            protocol: Some(net_client_protocol::Protocol::Pong(Pong { sequence: 1 }))
+        };
+        peer.write_all(&empty_frame()).await.unwrap();
+        peer.write_all(&serialize_protocol(ProtocolCategory::Net, &pong).unwrap()).await.unwrap();
+        peer.write_all(&halt_frame()).await.unwrap();
+
+        let (_, _, body) = in_message_rx.recv().await.unwrap();
+        assert!(body.is_empty());
+        let (_, _, body) = in_message_rx.recv().await.unwrap();
+        assert_eq!(body, halt_frame().slice(HEADER_SIZE..));
+
+        drop(peer);
+        assert!(matches!(handle.await.unwrap(), RecvResult::EOF));
+    }
+
+    #[tokio::test]
+    async fn test_recv_errors() {
+        // Declared length over the limit
+        let (mut peer, reader) = duplex(64);
+        let (handle, _in_message_rx, _retrieve_tx) = start(reader, LIMITS);
+        peer.write_all(&halt_frame()).await.unwrap();
+        assert!(matches!(handle.await.unwrap(), RecvResult::Error(RecvError::FrameTooLarge { max: 2, .. })));
+
+        // Closed in the middle of a frame
+        let limits = FrameLimits { max_frame_size: MAX_FRAME_SIZE, ..LIMITS };
+        let (mut peer, reader) = duplex(64);
+        let (handle, _in_message_rx, _retrieve_tx) = start(reader, limits);
+        peer.write_all(&halt_frame()[..HEADER_SIZE + 1]).await.unwrap();
+        drop(peer);
+        assert!(matches!(handle.await.unwrap(), RecvResult::Error(RecvError::Truncated)));
+
+        // Stalled in the middle of a frame
+        let (mut peer, reader) = duplex(64);
+        let (handle, _in_message_rx, _retrieve_tx) = start(reader, limits);
+        peer.write_all(&halt_frame()[..HEADER_SIZE + 1]).await.unwrap();
+        assert!(matches!(handle.await.unwrap(), RecvResult::Error(RecvError::ReadTimeout)));
+
+        // Nothing sent at all
+        let (_peer, reader) = duplex(64);
+        let (handle, _in_message_rx, _retrieve_tx) = start(reader, limits);
+        assert!(matches!(handle.await.unwrap(), RecvResult::Error(RecvError::IdleTimeout)));
+    }
+
+    #[tokio::test]
+    async fn test_recv_retrieve() {
+        let (_peer, reader) = duplex(64);
+        let (handle, _in_message_rx, retrieve_tx) = start(reader, LIMITS);
+        retrieve_tx.send(()).unwrap();
+        assert!(matches!(handle.await.unwrap(), RecvResult::Retrieve(..)));
+
+        // The room no longer receives
+        let (mut peer, reader) = duplex(64);
+        let (handle, in_message_rx, _retrieve_tx) = start(reader, LIMITS);
+        drop(in_message_rx);
+        peer.write_all(&empty_frame()).await.unwrap();
+        assert!(matches!(handle.await.unwrap(), RecvResult::Closed));
+    }
+
+    #[test]
+    fn test_heartbeat() {
+        let mut heartbeat = Heartbeat::default();
+        let now = Instant::now();
+
+        let sequence = heartbeat.ping(now);
+        heartbeat.pong(sequence, now + Duration::from_millis(100));
+        assert_eq!(heartbeat.rtt, Some(Duration::from_millis(100)));
+        assert_eq!(heartbeat.jitter, Duration::ZERO);
+
+        let sequence = heartbeat.ping(now);
+        heartbeat.pong(sequence, now + Duration::from_millis(200));
+        assert_eq!(heartbeat.rtt.unwrap().as_micros(), 112_500);
+        assert_eq!(heartbeat.jitter.as_micros(), 6_250);
+
+        // Late answers don't count
+        let sequence = heartbeat.ping(now);
+        heartbeat.ping(now);
+        heartbeat.pong(sequence, now);
+        assert_eq!(heartbeat.missed, 1);
+        heartbeat.ping(now);
+        assert_eq!(heartbeat.missed, 2);
+    }
+
+    #[tokio::test]
+    async fn test_retrieve_and_resume() {
+        let (mut peer, stream) = connect().await;
+
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let (in_message_tx, mut in_message_rx) = mpsc::channel(8);
+        let (server_message_tx, _server_message_rx) = mpsc::channel(1);
+        let server_ctx = Arc::new(ServerContext::new(server_message_tx));
+        let ctx = run_session(stream, in_message_tx, shutdown_rx.resubscribe(), HEARTBEAT, server_ctx);
+
+        peer.write_all(&empty_frame()).await.unwrap();
+        assert!(in_message_rx.recv().await.is_some());
+
+        // Neither lost nor duplicated while paused
+        let session = ctx.retrieve().await.unwrap();
+        peer.write_all(&halt_frame()).await.unwrap();
+        ctx.send(halt_frame());
+        assert!(!ctx.is_closed());
+
+        let (target_tx, mut target_rx) = mpsc::channel(8);
+        resume_session(session, target_tx, shutdown_rx);
+        let (resumed_ctx, _, body) = target_rx.recv().await.unwrap();
+        assert!(resumed_ctx.is_same(&ctx));
+        assert_eq!(body, halt_frame().slice(HEADER_SIZE..));
+        assert!(in_message_rx.recv().await.is_none());
+
+        let mut buf = vec![0u8; halt_frame().len()];
+        peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf[..], &halt_frame()[..]);
+    }
+
+    #[test]
+    fn test_outbound() {
+        let (close_tx, mut close_rx) = mpsc::channel(1);
+        let (retrieve_tx, _) = mpsc::channel(1);
+        let ctx = SessionContext::new(SocketAddr::from(([127, 0, 0, 1], 0)), close_tx, retrieve_tx);
+        let movement = |entity, state| Movement { entity, state, ..Default::default() };
+
+        // Movements are merged into the pending sync, the latest of an entity winning,
+        // unless other frames are queued after it
+        ctx.send_movements(vec![movement(1, 0), movement(2, 0)]);
+        ctx.send_movements(vec![movement(1, 1)]);
+        ctx.send(halt_frame());
+        ctx.send_movements(vec![movement(2, 1)]);
+        for _ in 3..OUT_QUEUE_CAPACITY {
+            ctx.send_sync(empty_frame());
+        }
+
+        // The oldest sync frames make room for the new ones
+        ctx.send(halt_frame());
+        ctx.send_sync(empty_frame());
+        let stats = ctx.outbound_stats();
+        assert_eq!((stats.depth, stats.peak_depth, stats.dropped, stats.coalesced), (64, 64, 2, 1));
+
+        let movement_sync = |movements| {
+            let protocol = GameServerProtocol {
+                protocol: Some(game_server_protocol::Protocol::MovementSync(MovementSync { movements }))
+            };
+            serialize_protocol(ProtocolCategory::Game, &protocol).unwrap()
+        };
+        let mut queue = ctx.outbound.queue.lock().unwrap();
+        assert_eq!(queue.pop().unwrap(), movement_sync(vec![movement(1, 1), movement(2, 0)]));
+        assert_eq!(queue.pop().unwrap(), halt_frame());
+        assert_eq!(queue.pop().unwrap(), movement_sync(vec![movement(2, 1)]));
+        drop(queue);
+
+        // Too slow once reliable messages no longer fit
+        assert!(close_rx.try_recv().is_err());
+        for _ in 0..OUT_QUEUE_CAPACITY + 2 {
+            ctx.send(halt_frame());
+        }
+        assert_eq!(close_rx.try_recv().unwrap(), CloseReason::SlowClient);
+    }
+
+    #[tokio::test]
+    async fn test_close() {
+        let (mut peer, stream) = connect().await;
+
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let (in_message_tx, _in_message_rx) = mpsc::channel(8);
+        let (server_message_tx, mut server_message_rx) = mpsc::channel(1);
+        let server_ctx = Arc::new(ServerContext::new(server_message_tx));
+        let ctx = run_session(stream, in_message_tx, shutdown_rx, HEARTBEAT, server_ctx);
+
+        // Pending messages are flushed before the disconnect
+        ctx.send(halt_frame());
+        ctx.close(CloseReason::Kicked);
+
+        let mut buf = Vec::new();
+        peer.read_to_end(&mut buf).await.unwrap();
+        let disconnect = NetServerProtocol {
+            protocol: Some(net_server_protocol::Protocol::Disconnect(Disconnect { reason: CloseReason::Kicked as i32 }))
+        };
+        let expected = [halt_frame(), serialize_protocol(ProtocolCategory::Net, &disconnect).unwrap()].concat();
+        assert_eq!(buf, expected);
+
+        let Some(ServerMessage::SessionClosed { session_ctx, reason }) = server_message_rx.recv().await else {
+            panic!("SessionClosed expected");
+        };
+        assert!(session_ctx.is_same(&ctx) && session_ctx.is_closed());
+        assert_eq!(ctx.end_reason(), Some(CloseReason::Kicked));
+        assert_eq!(reason, CloseReason::Kicked);
+    }
+
+    #[tokio::test]
+    async fn test_close_retrieved() {
+        let (mut peer, stream) = connect().await;
+
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let (in_message_tx, _in_message_rx) = mpsc::channel(8);
+        let (server_message_tx, mut server_message_rx) = mpsc::channel(1);
+        let server_ctx = Arc::new(ServerContext::new(server_message_tx));
+        let ctx = run_session(stream, in_message_tx, shutdown_rx, HEARTBEAT, server_ctx);
+
+        // Told and reported as well, without being resumed
+        ctx.retrieve().await.unwrap().close(CloseReason::InternalError);
+
+        let mut buf = Vec::new();
+        peer.read_to_end(&mut buf).await.unwrap();
+        let disconnect = NetServerProtocol {
+            protocol: Some(net_server_protocol::Protocol::Disconnect(Disconnect { reason: CloseReason::InternalError as i32 }))
+        };
+        assert_eq!(buf, serialize_protocol(ProtocolCategory::Net, &disconnect).unwrap());
+
+        let Some(ServerMessage::SessionClosed { reason, .. }) = server_message_rx.recv().await else {
+            panic!("SessionClosed expected");
+        };
+        assert_eq!(reason, CloseReason::InternalError);
     }
 }
@@ -1,18 +1,206 @@
 use bevy_ecs::prelude::*;
 use nalgebra::{distance, Point2, Vector2};
 use crate::physics::object::*;
+use crate::world::chunk::ChunkGrid;
+use std::collections::{HashMap, HashSet};
 
+/// Shapes are axis-aligned, and `w`, `h` of a rectangle are the half extents.
 pub enum CollisionShape {
     Rectangle { w: f32, h: f32 },
     Circle { radius: f32 },
 }
 
-#[derive(Component)]
+impl CollisionShape {
+    pub fn bounding_radius(&self) -> f32 {
+        match self {
+            CollisionShape::Rectangle { w, h } => (w * w + h * h).sqrt(),
+            CollisionShape::Circle { radius } => *radius,
+        }
+    }
+}
+
+/// Penetration of two shapes. `normal` points from the first shape to the second.
+#[derive(Debug, PartialEq)]
+pub struct Contact {
+    pub normal: Vector2<f32>,
+    pub depth: f32,
+}
+
+/// A kinematic body `a` has collided with a static or kinematic body `b`.
+#[derive(Event)]
 pub struct Collision {
     pub a: Entity,
     pub b: Entity,
 }
 
+#[derive(Event)]
+pub struct TriggerEnter {
+    pub trigger: Entity,
+    pub other: Entity,
+}
+
+#[derive(Event)]
+pub struct TriggerExit {
+    pub trigger: Entity,
+    pub other: Entity,
+}
+
+/// Kinematic bodies currently overlapping each trigger, as (trigger, other) pairs.
+#[derive(Resource, Default)]
+pub struct TriggerContacts {
+    contacts: HashSet<(Entity, Entity)>,
+}
+
+impl TriggerContacts {
+    pub fn is_inside(&self, trigger: Entity, other: Entity) -> bool {
+        self.contacts.contains(&(trigger, other))
+    }
+}
+
+pub fn contact(
+    pa: Point2<f32>,
+    a: &CollisionShape,
+    pb: Point2<f32>,
+    b: &CollisionShape,
+) -> Option<Contact> {
+    match (a, b) {
+        (CollisionShape::Circle { radius: ra }, CollisionShape::Circle { radius: rb }) => {
+            let d = pb - pa;
+            let dist = d.norm();
+            let r = ra + rb;
+            if dist >= r {
+                return None;
+            }
+
+            let normal = if dist > f32::EPSILON { d / dist } else { Vector2::x() };
+            Some(Contact { normal, depth: r - dist })
+        }
+        (CollisionShape::Rectangle { w: wa, h: ha }, CollisionShape::Rectangle { w: wb, h: hb }) => {
+            let d = pb - pa;
+            let overlap_x = wa + wb - d.x.abs();
+            let overlap_y = ha + hb - d.y.abs();
+            if overlap_x <= 0.0 || overlap_y <= 0.0 {
+                return None;
+            }
+
+            if overlap_x < overlap_y {
+                Some(Contact { normal: Vector2::new(sign(d.x), 0.0), depth: overlap_x })
+            } else {
+                Some(Contact { normal: Vector2::new(0.0, sign(d.y)), depth: overlap_y })
+            }
+        }
+        (CollisionShape::Rectangle { w, h }, CollisionShape::Circle { radius }) => {
+            let d = pb - pa;
+            let closest = Vector2::new(d.x.clamp(-w, *w), d.y.clamp(-h, *h));
+
+            if closest != d {
+                // Circle center is outside the rectangle
+                let diff = d - closest;
+                let dist = diff.norm();
+                if dist >= *radius {
+                    return None;
+                }
+
+                return Some(Contact { normal: diff / dist, depth: radius - dist });
+            }
+
+            // Circle center is inside the rectangle, push out through the nearest edge
+            let edge_x = w - d.x.abs();
+            let edge_y = h - d.y.abs();
+            if edge_x < edge_y {
+                Some(Contact { normal: Vector2::new(sign(d.x), 0.0), depth: radius + edge_x })
+            } else {
+                Some(Contact { normal: Vector2::new(0.0, sign(d.y)), depth: radius + edge_y })
+            }
+        }
+        (CollisionShape::Circle { .. }, CollisionShape::Rectangle { .. }) => {
+            contact(pb, b, pa, a).map(|c| Contact { normal: -c.normal, depth: c.depth })
+        }
+    }
+}
+
+fn sign(v: f32) -> f32 {
+    if v < 0.0 { -1.0 } else { 1.0 }
+}
+
+pub fn update(
+    grid: Res<ChunkGrid>,
+    mut kinematic_bodies: Query<(Entity, &mut Transform, &KinematicBody)>,
+    static_bodies: Query<(&Transform, &StaticBody), Without<KinematicBody>>,
+    trigger_bodies: Query<(&Transform, &TriggerBody), Without<KinematicBody>>,
+    mut trigger_contacts: ResMut<TriggerContacts>,
+    mut collision_events: EventWriter<Collision>,
+    mut trigger_enter_events: EventWriter<TriggerEnter>,
+    mut trigger_exit_events: EventWriter<TriggerExit>,
+) {
+    let mut corrections: HashMap<Entity, Vector2<f32>> = HashMap::new();
+    let mut contacts = HashSet::new();
+
+    kinematic_bodies.iter().for_each(|(a, transform, body)| {
+        // Widened so that large bodies centered in farther chunks are found too
+        let radius = body.shape.bounding_radius() + grid.max_extent();
+        let candidates = grid.entities_around(&transform.position, radius);
+
+        for &b in candidates {
+            if a == b {
+                continue;
+            }
+
+            if let Ok((other, other_body)) = static_bodies.get(b) {
+                if let Some(c) = contact(transform.position, &body.shape, other.position, &other_body.shape) {
+                    *corrections.entry(a).or_default() -= c.normal * c.depth;
+                    collision_events.send(Collision { a, b });
+                }
+            } else if let Ok((_, other, other_body)) = kinematic_bodies.get(b) {
+                // Resolve each kinematic pair once, splitting the correction
+                if a > b {
+                    continue;
+                }
+
+                if let Some(c) = contact(transform.position, &body.shape, other.position, &other_body.shape) {
+                    let correction = c.normal * (c.depth * 0.5);
+                    *corrections.entry(a).or_default() -= correction;
+                    *corrections.entry(b).or_default() += correction;
+                    collision_events.send(Collision { a, b });
+                    collision_events.send(Collision { a: b, b: a });
+                }
+            }
+
+            if let Ok((other, other_body)) = trigger_bodies.get(b) {
+                if contact(transform.position, &body.shape, other.position, &other_body.shape).is_some() {
+                    contacts.insert((b, a));
+                }
+            }
+        }
+    });
+
+    for (entity, correction) in corrections {
+        let Ok((_, mut transform, _)) = kinematic_bodies.get_mut(entity) else {
+            continue;
+        };
+
+        transform.position += correction;
+
+        // Stop moving into the obstacle
+        let norm = correction.norm();
+        if norm > f32::EPSILON {
+            let normal = correction / norm;
+            let into = transform.velocity.dot(&normal);
+            if into < 0.0 {
+                transform.velocity -= normal * into;
+            }
+        }
+    }
+
+    for &(trigger, other) in contacts.difference(&trigger_contacts.contacts) {
+        trigger_enter_events.send(TriggerEnter { trigger, other });
+    }
+    for &(trigger, other) in trigger_contacts.contacts.difference(&contacts) {
+        trigger_exit_events.send(TriggerExit { trigger, other });
+    }
+    trigger_contacts.contacts = contacts;
+}
+
 pub fn dotcast(dot: Point2<f32>, p: Point2<f32>, c: &CollisionShape) -> bool {
     match c {
         CollisionShape::Rectangle { w, h } => {
@@ -25,30 +213,267 @@ pub fn dotcast(dot: Point2<f32>, p: Point2<f32>, c: &CollisionShape) -> bool {
     }
 }
 
+#[derive(Debug)]
+pub struct RaycastHit {
+    pub entity: Entity,
+    pub point: Point2<f32>,
+    pub normal: Vector2<f32>,
+    pub distance: f32,
+}
+
+/// Casts the ray segment from `p` to `p + v` against a shape at `position`.
+/// Returns the hit ratio along `v` in `[0, 1]` and the surface normal.
+pub fn raycast_shape(
+    p: Point2<f32>,
+    v: Vector2<f32>,
+    position: Point2<f32>,
+    c: &CollisionShape,
+) -> Option<(f32, Vector2<f32>)> {
+    if v == Vector2::zeros() {
+        return None;
+    }
+
+    if dotcast(p, position, c) {
+        // The ray starts inside the shape
+        return Some((0.0, -v.normalize()));
+    }
+
+    let d = p - position;
+    match c {
+        CollisionShape::Rectangle { w, h } => {
+            let mut t_min = 0.0f32;
+            let mut t_max = 1.0f32;
+            let mut normal = Vector2::zeros();
+
+            for (axis, extent) in [(0, *w), (1, *h)] {
+                if v[axis].abs() < f32::EPSILON {
+                    if d[axis].abs() > extent {
+                        return None;
+                    }
+                    continue;
+                }
+
+                let t1 = (-extent - d[axis]) / v[axis];
+                let t2 = (extent - d[axis]) / v[axis];
+                let (t_near, t_far) = if t1 < t2 { (t1, t2) } else { (t2, t1) };
+
+                if t_near > t_min {
+                    t_min = t_near;
+                    normal = Vector2::zeros();
+                    normal[axis] = -sign(v[axis]);
+                }
+                t_max = t_max.min(t_far);
+
+                if t_min > t_max {
+                    return None;
+                }
+            }
+
+            Some((t_min, normal))
+        }
+        CollisionShape::Circle { radius } => {
+            let a = v.dot(&v);
+            let b = 2.0 * d.dot(&v);
+            let k = d.dot(&d) - radius * radius;
+
+            let discriminant = b * b - 4.0 * a * k;
+            if discriminant < 0.0 {
+                return None;
+            }
+
+            let t = (-b - discriminant.sqrt()) / (2.0 * a);
+            if !(0.0..=1.0).contains(&t) {
+                return None;
+            }
+
+            let normal = (d + v * t) / *radius;
+            Some((t, normal))
+        }
+    }
+}
+
 pub fn raycast(
     p: Point2<f32>,
     v: Vector2<f32>,
-    static_bodies: Query<&StaticBody>,
-    kinematic_bodies: Query<&KinematicBody>,
-) -> Option<Entity> {
-    None
+    grid: &ChunkGrid,
+    static_bodies: &Query<(Entity, &Transform, &StaticBody)>,
+    kinematic_bodies: &Query<(Entity, &Transform, &KinematicBody)>,
+) -> Option<RaycastHit> {
+    raycast_many(p, v, grid, static_bodies, kinematic_bodies).into_iter().next()
 }
 
+/// Hits sorted by distance, nearest first.
 pub fn raycast_many(
     p: Point2<f32>,
     v: Vector2<f32>,
-    static_bodies: Query<&StaticBody>,
-    kinematic_bodies: Query<&KinematicBody>,
-) -> Vec<Entity> {
-    let mut result: Vec<Entity> = Vec::new();
-    
-    static_bodies.iter().for_each(|body| {
-        
-    });
-    
-    kinematic_bodies.iter().for_each(|body| {
-        
-    });
-    
+    grid: &ChunkGrid,
+    static_bodies: &Query<(Entity, &Transform, &StaticBody)>,
+    kinematic_bodies: &Query<(Entity, &Transform, &KinematicBody)>,
+) -> Vec<RaycastHit> {
+    let mut result: Vec<RaycastHit> = Vec::new();
+    let length = v.norm();
+
+    let mut cast = |entity: Entity, position: Point2<f32>, shape: &CollisionShape| {
+        if let Some((t, normal)) = raycast_shape(p, v, position, shape) {
+            result.push(RaycastHit {
+                entity,
+                point: p + v * t,
+                normal,
+                distance: t * length,
+            });
+        }
+    };
+
+    for &entity in grid.entities_along(&p, &v, grid.max_extent()) {
+        if let Ok((_, transform, body)) = static_bodies.get(entity) {
+            cast(entity, transform.position, &body.shape);
+        } else if let Ok((_, transform, body)) = kinematic_bodies.get(entity) {
+            cast(entity, transform.position, &body.shape);
+        }
+    }
+
+    result.sort_by(|a, b| a.distance.total_cmp(&b.distance));
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::chunk::{self, CHUNK_SIZE};
+    use bevy_ecs::system::RunSystemOnce;
+
+    #[test]
+    fn test_dotcast() {
+        let rect = CollisionShape::Rectangle { w: 1.0, h: 2.0 };
+        assert!(dotcast(Point2::new(1.0, -2.0), Point2::new(0.0, 0.0), &rect));
+        assert!(!dotcast(Point2::new(1.5, 0.0), Point2::new(0.0, 0.0), &rect));
+
+        let circle = CollisionShape::Circle { radius: 1.0 };
+        assert!(dotcast(Point2::new(1.0, 1.0), Point2::new(1.0, 0.5), &circle));
+        assert!(!dotcast(Point2::new(1.0, 1.0), Point2::new(0.0, 0.0), &circle));
+    }
+
+    #[test]
+    fn test_raycast_rectangle() {
+        let rect = CollisionShape::Rectangle { w: 1.0, h: 1.0 };
+
+        let (t, normal) = raycast_shape(Point2::new(-5.0, 0.5), Vector2::new(10.0, 0.0), Point2::new(0.0, 0.0), &rect).unwrap();
+        assert!((t - 0.4).abs() < 1e-6);
+        assert_eq!(normal, Vector2::new(-1.0, 0.0));
+
+        let (t, normal) = raycast_shape(Point2::new(0.0, 5.0), Vector2::new(0.0, -10.0), Point2::new(0.0, 0.0), &rect).unwrap();
+        assert!((t - 0.4).abs() < 1e-6);
+        assert_eq!(normal, Vector2::new(0.0, 1.0));
+
+        // Too short, and passing by
+        assert!(raycast_shape(Point2::new(-5.0, 0.0), Vector2::new(3.0, 0.0), Point2::new(0.0, 0.0), &rect).is_none());
+        assert!(raycast_shape(Point2::new(-5.0, 2.0), Vector2::new(10.0, 0.0), Point2::new(0.0, 0.0), &rect).is_none());
+    }
+
+    #[test]
+    fn test_raycast_circle() {
+        let circle = CollisionShape::Circle { radius: 1.0 };
+
+        let (t, normal) = raycast_shape(Point2::new(-5.0, 0.0), Vector2::new(10.0, 0.0), Point2::new(0.0, 0.0), &circle).unwrap();
+        assert!((t - 0.4).abs() < 1e-6);
+        assert!((normal - Vector2::new(-1.0, 0.0)).norm() < 1e-6);
+
+        assert!(raycast_shape(Point2::new(-5.0, 1.5), Vector2::new(10.0, 0.0), Point2::new(0.0, 0.0), &circle).is_none());
+        assert!(raycast_shape(Point2::new(5.0, 0.0), Vector2::new(10.0, 0.0), Point2::new(0.0, 0.0), &circle).is_none());
+    }
+
+    #[test]
+    fn test_raycast_many() {
+        let mut world = World::default();
+        let far = world.spawn((
+            Transform { position: Point2::new(8.0, 0.0), ..Default::default() },
+            StaticBody { shape: CollisionShape::Rectangle { w: 1.0, h: 1.0 } },
+        )).id();
+        let near = world.spawn((
+            Transform { position: Point2::new(4.0, 0.0), ..Default::default() },
+            KinematicBody { shape: CollisionShape::Circle { radius: 1.0 } },
+        )).id();
+        world.spawn((
+            Transform { position: Point2::new(4.0, 5.0), ..Default::default() },
+            StaticBody { shape: CollisionShape::Circle { radius: 1.0 } },
+        ));
+
+        world.init_resource::<ChunkGrid>();
+        world.run_system_once(chunk::update).unwrap();
+
+        let hits = world.run_system_once(|
+            grid: Res<ChunkGrid>,
+            static_bodies: Query<(Entity, &Transform, &StaticBody)>,
+            kinematic_bodies: Query<(Entity, &Transform, &KinematicBody)>,
+        | {
+            raycast_many(Point2::new(0.0, 0.0), Vector2::new(10.0, 0.0), &grid, &static_bodies, &kinematic_bodies)
+        }).unwrap();
+
+        assert_eq!(hits.iter().map(|hit| hit.entity).collect::<Vec<_>>(), vec![near, far]);
+        assert!((hits[0].distance - 3.0).abs() < 1e-6);
+        assert!((hits[1].point - Point2::new(7.0, 0.0)).norm() < 1e-6);
+    }
+
+    #[test]
+    fn test_collision_large_static() {
+        let mut world = World::default();
+        world.init_resource::<ChunkGrid>();
+        world.init_resource::<TriggerContacts>();
+        world.init_resource::<Events<Collision>>();
+        world.init_resource::<Events<TriggerEnter>>();
+        world.init_resource::<Events<TriggerExit>>();
+
+        // The wall is centered chunks away from its edge
+        world.spawn((
+            Transform { position: Point2::new(0.0, 0.0), ..Default::default() },
+            StaticBody { shape: CollisionShape::Rectangle { w: CHUNK_SIZE * 4.0, h: 1.0 } },
+        ));
+        let body = world.spawn((
+            Transform { position: Point2::new(CHUNK_SIZE * 3.5, 1.5), ..Default::default() },
+            KinematicBody { shape: CollisionShape::Circle { radius: 1.0 } },
+        )).id();
+
+        world.run_system_once(chunk::update).unwrap();
+        world.run_system_once(update).unwrap();
+        let position = world.get::<Transform>(body).unwrap().position;
+        assert!((position.y - 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_contact_circles() {
+        let circle = CollisionShape::Circle { radius: 1.0 };
+
+        let c = contact(Point2::new(0.0, 0.0), &circle, Point2::new(1.5, 0.0), &circle).unwrap();
+        assert_eq!(c.normal, Vector2::new(1.0, 0.0));
+        assert!((c.depth - 0.5).abs() < 1e-6);
+
+        assert_eq!(contact(Point2::new(0.0, 0.0), &circle, Point2::new(2.0, 0.0), &circle), None);
+    }
+
+    #[test]
+    fn test_contact_rectangles() {
+        let rect = CollisionShape::Rectangle { w: 1.0, h: 2.0 };
+
+        let c = contact(Point2::new(0.0, 0.0), &rect, Point2::new(-1.5, 1.0), &rect).unwrap();
+        assert_eq!(c.normal, Vector2::new(-1.0, 0.0));
+        assert!((c.depth - 0.5).abs() < 1e-6);
+
+        assert_eq!(contact(Point2::new(0.0, 0.0), &rect, Point2::new(0.0, 4.5), &rect), None);
+    }
+
+    #[test]
+    fn test_contact_rectangle_circle() {
+        let rect = CollisionShape::Rectangle { w: 1.0, h: 1.0 };
+        let circle = CollisionShape::Circle { radius: 1.0 };
+
+        let c = contact(Point2::new(0.0, 0.0), &rect, Point2::new(0.0, 1.5), &circle).unwrap();
+        assert_eq!(c.normal, Vector2::new(0.0, 1.0));
+        assert!((c.depth - 0.5).abs() < 1e-6);
+
+        let c = contact(Point2::new(0.0, 1.5), &circle, Point2::new(0.0, 0.0), &rect).unwrap();
+        assert_eq!(c.normal, Vector2::new(0.0, -1.0));
+
+        // Corner is farther than the radius
+        assert_eq!(contact(Point2::new(0.0, 0.0), &rect, Point2::new(1.8, 1.8), &circle), None);
+    }
+}
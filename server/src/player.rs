@@ -3,28 +3,55 @@ pub mod account;
 use bevy_ecs::prelude::*;
 use crate::character::*;
 use crate::character::movement::MovementController;
+use crate::character::resource::*;
 use crate::character::stat::*;
 use crate::character::status_effect::*;
-use crate::core::session::Session;
-use crate::physics::object::Transform;
+use crate::character::vision::Visibility;
+use crate::core::room_resource::ServerHandle;
+use crate::core::server::ServerMessage;
+use crate::core::session::{CloseReason, Latency, Session};
+use crate::physics::collision::CollisionShape;
+use crate::physics::object::{KinematicBody, Transform};
 use crate::player::account::*;
+use crate::world::environment::Environment;
+use crate::world::interest::Interest;
+use crate::world::time::WorldTime;
 use std::error::Error;
+use std::time::SystemTime;
 use tokio_postgres::Client;
 
+/// Radius of the body of every player.
+pub const PLAYER_RADIUS: f32 = 0.5;
+/// Units per millisecond, the same for every player until it's stored with the stats.
+const PLAYER_BASE_SPEED: f32 = 0.005;
+
 #[derive(Bundle)]
 pub struct PlayerBundle {
     // network
     pub account: Account,
     pub session: Session,
+    pub latency: Latency,
 
     // character
     pub character: Character,
     pub character_stat: CharacterStat,
-    // pub mobility_stat: MobilityStat,
+    pub mobility_stat: MobilityStat,
+    pub health: Health,
+    pub mana: Mana,
+    pub rage: Rage,
+    pub stamina: Stamina,
+    pub regeneration: Regeneration,
+    pub status_effect_controller: StatusEffectController,
 
     // movement
     pub transform: Transform,
     pub movement_controller: MovementController,
+    pub body: KinematicBody,
+    pub visibility: Visibility,
+
+    // world
+    pub interest: Interest,
+    pub environment: Environment,
 }
 
 
@@ -37,16 +64,67 @@ impl PlayerBundle {
     ) -> Result<Box<Self>, Box<dyn Error>> {
         let character = Character::load(character_id, client).await?;
         let character_stat = CharacterStat::load(character_id, client).await?;
+        let status_effect_controller = StatusEffectController::load(character_id, client).await?;
+
+        Ok(Box::new(PlayerBundle::new(account, session, character, character_stat, status_effect_controller)))
+    }
 
-        Ok(Box::new(PlayerBundle {
+    pub fn new(
+        account: Account,
+        session: Session,
+        character: Character,
+        character_stat: CharacterStat,
+        status_effect_controller: StatusEffectController,
+    ) -> Self {
+        PlayerBundle {
             account,
             session,
+            latency: Latency::default(),
 
             character,
+            mobility_stat: MobilityStat::new(PLAYER_BASE_SPEED),
+            health: Health::new(max_health(&character_stat)),
+            mana: Mana::new(max_mana(&character_stat)),
+            rage: Rage::new(),
+            stamina: Stamina::new(max_stamina(&character_stat)),
+            regeneration: Regeneration::default(),
+            status_effect_controller,
             character_stat,
 
             transform: Transform::default(),
             movement_controller: MovementController::default(),
-        }))
+            body: KinematicBody { shape: CollisionShape::Circle { radius: PLAYER_RADIUS } },
+            visibility: Visibility::new(true),
+
+            interest: Interest::default(),
+            environment: Environment::default(),
+        }
     }
-}
\ No newline at end of file
+}
+
+/// Despawns the players whose session is closed, handing their state over to the server to be saved.
+pub fn leave(
+    mut commands: Commands,
+    players: Query<(Entity, &Session, &Character, Option<&StatusEffectController>), With<Account>>,
+    time: Res<WorldTime>,
+    server: Res<ServerHandle>,
+) {
+    players.iter().for_each(|(entity, session, character, status)| {
+        if !session.ctx.is_closed() {
+            return;
+        }
+        let reason = session.ctx.end_reason().unwrap_or(CloseReason::ConnectionLost);
+        println!("Player left: character_id={}, reason={:?}", character.id, reason);
+
+        let status_effects = status
+            .map(|status| status.snapshot(time.now, SystemTime::now()))
+            .unwrap_or_default();
+        commands.entity(entity).despawn();
+
+        let message_tx = server.ctx.message_tx.clone();
+        let character_id = character.id;
+        tokio::spawn(async move {
+            _ = message_tx.send(ServerMessage::PlayerLeft { character_id, status_effects }).await;
+        });
+    });
+}
@@ -1,83 +1,270 @@
-use crate::core::room::{handle_room_message, RoomContext};
+use crate::character::audition::{self, Sound};
+use crate::character::cognition;
+use crate::character::combat::{self, Hit, Strike};
+use crate::character::movement::{self, MovementCommand, MovementController, MovementViolations};
+use crate::character::movement::tuning::{self, MovementTuning, TUNING_FILE};
+use crate::character::resource::{self, Died};
+use crate::character::status_effect;
+use crate::character::vision::{self, SightEntered, SightLeft};
+use crate::core::config::DataConfig;
+use crate::core::room::*;
+use crate::core::room_resource::SessionRegistry;
 use crate::core::server::ServerContext;
-use crate::core::session::{InMessage, SessionContext};
+use crate::core::session::{self, CloseReason, InMessage, SessionContext};
+use crate::physics::collision::{self, Collision, TriggerContacts, TriggerEnter, TriggerExit};
+use crate::player;
 use crate::protocol::*;
+use crate::protocol::game::{GameClientProtocol, MovementMode, game_client_protocol};
 use crate::protocol::net::{*, net_client_protocol::Protocol};
+use crate::world::chunk::{self, ChunkGrid};
+use crate::world::environment;
+use crate::world::interest::{self, ViewEntered, ViewLeft};
+use bevy_ecs::prelude::*;
+use nalgebra::UnitVector2;
 use std::sync::Arc;
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::broadcast;
+use tokio::time;
+
+const UPDATE_INTERVAL: time::Duration = time::Duration::from_millis(50);
 
 pub fn run(
     server_ctx: Arc<ServerContext>,
-    mut shutdown_rx: broadcast::Receiver<()>,
+    shutdown_rx: broadcast::Receiver<()>,
 ) -> Arc<RoomContext> {
-    let (room_message_tx, mut room_message_rx) = mpsc::channel(16);
-    let (in_message_tx, mut in_message_rx) = mpsc::channel(64);
-
-    let ctx = Arc::new(RoomContext::new(room_message_tx, in_message_tx));
-    let ctx_handle = ctx.clone();
-
-    tokio::spawn(async move {
-        let mut room_message_buffer = Vec::with_capacity(16);
-        let mut in_message_buffer = Vec::with_capacity(64);
-
-        loop {
-            tokio::select! {
-                n = in_message_rx.recv_many(&mut in_message_buffer, 64) => {
-                    if n == 0 {
-                        break;
-                    }
-
-                    for in_message in in_message_buffer.drain(0..n) {
-                        handle_in_message(&server_ctx, in_message).await;
-                    }
-                },
-                n = room_message_rx.recv_many(&mut room_message_buffer, 16) => {
-                    if n == 0 {
-                        break;
-                    }
-
-                    for room_message in room_message_buffer.drain(0..n) {
-                        handle_room_message(&ctx_handle, room_message).await;
-                    }
-                },
-                _ = shutdown_rx.recv() => break,
-            }
-        }
-    });
+    let data_config = DataConfig::load();
+    let movement_tuning = MovementTuning::load(&data_config.dir.join(TUNING_FILE));
+
+    let builder = RoomBuilder::default()
+        .add_in_message_handler(handle_in_message)
+        .add_in_message_handler(handle_game_in_message)
+        .set_update_interval(UPDATE_INTERVAL)
+        .init_resource::<ChunkGrid>()
+        .init_resource::<TriggerContacts>()
+        .init_resource::<MovementViolations>()
+        .insert_resource(movement_tuning)
+        .add_event::<Collision>()
+        .add_event::<TriggerEnter>()
+        .add_event::<TriggerExit>()
+        .add_event::<Strike>()
+        .add_event::<Hit>()
+        .add_event::<Died>()
+        .add_event::<Sound>()
+        .add_event::<SightEntered>()
+        .add_event::<SightLeft>()
+        .add_event::<ViewEntered>()
+        .add_event::<ViewLeft>()
+        .add_systems((
+            status_effect::update,
+            cognition::update,
+            movement::validate,
+            environment::update,
+            movement::update,
+            chunk::update,
+            collision::update,
+            combat::update,
+            combat::resolve,
+            resource::update_max_values,
+            movement::drain_stamina,
+            resource::regenerate,
+            resource::update_death,
+            audition::emit_footsteps,
+            audition::update_hearing,
+            vision::update_sight,
+            interest::update,
+            movement::sync,
+            resource::sync,
+            player::leave,
+        ).chain())
+        .add_systems((tuning::reload, session::update_latency).before(movement::validate));
 
-    ctx
+    run_room(builder, server_ctx, shutdown_rx)
 }
 
-async fn handle_in_message(server_ctx: &Arc<ServerContext>, message: InMessage) {
+fn handle_in_message(
+    message: &InMessage,
+    _server_ctx: &Arc<ServerContext>,
+    _world: &mut World,
+) -> InMessageHandleResult {
     let (session_ctx, category, data) = message;
-    if category != ProtocolCategory::Net {
-        eprintln!("Protocol not net: {:?}", category);
-        session_ctx.close().await;
-        return;
+    if *category != ProtocolCategory::Net {
+        return InMessageHandleResult::Pass;
     }
 
-    let protocol = NetClientProtocol::decode(data);
+    let protocol = NetClientProtocol::decode(data.clone());
     if let Err(e) = protocol {
         eprintln!("Failed to decode net protocol: {}", e);
-        session_ctx.close().await;
-        return;
+        session_ctx.close(CloseReason::ProtocolError);
+        return InMessageHandleResult::Break;
     }
 
     match protocol.unwrap().protocol {
         Some(Protocol::RoomTransferReady(ready)) => {
-            handle_room_transfer_ready(&server_ctx, session_ctx, ready).await;
+            handle_room_transfer_ready(session_ctx, ready);
         }
         None => {
-            _ = session_ctx.close_tx.send(());
+            session_ctx.close(CloseReason::ProtocolError);
         }
         _ => {}
     }
+
+    InMessageHandleResult::Break
 }
 
-async fn handle_room_transfer_ready(
-    server_ctx: &Arc<ServerContext>,
-    session_ctx: Arc<SessionContext>,
+fn handle_room_transfer_ready(
+    session_ctx: &SessionContext,
     ready: RoomTransferReady,
 ) {
 
-}
\ No newline at end of file
+}
+
+fn handle_game_in_message(
+    message: &InMessage,
+    _server_ctx: &Arc<ServerContext>,
+    world: &mut World,
+) -> InMessageHandleResult {
+    let (session_ctx, category, data) = message;
+    if *category != ProtocolCategory::Game {
+        return InMessageHandleResult::Pass;
+    }
+
+    let protocol = match GameClientProtocol::decode(data.clone()) {
+        Ok(protocol) => protocol,
+        Err(e) => {
+            eprintln!("Failed to decode game protocol: {}", e);
+            session_ctx.close(CloseReason::ProtocolError);
+            return InMessageHandleResult::Break;
+        }
+    };
+
+    let Some(protocol) = protocol.protocol else {
+        session_ctx.close(CloseReason::ProtocolError);
+        return InMessageHandleResult::Break;
+    };
+
+    let (entity, command) = match protocol {
+        game_client_protocol::Protocol::Move(movement) => (
+            movement.entity,
+            to_direction(movement.direction).map(|direction| if movement.run {
+                MovementCommand::Run { direction }
+            } else {
+                MovementCommand::Walk { direction }
+            }),
+        ),
+        game_client_protocol::Protocol::Halt(halt) => (halt.entity, Some(MovementCommand::Halt)),
+        game_client_protocol::Protocol::Roll(roll) => (
+            roll.entity,
+            to_direction(roll.direction).map(|direction| MovementCommand::Roll { direction }),
+        ),
+        game_client_protocol::Protocol::ChangeMode(change) => (change.entity, to_mode_command(change.mode)),
+        game_client_protocol::Protocol::SyncPosition(sync) => (
+            sync.entity,
+            sync.position.map(|position| MovementCommand::Teleport { position: position.into(), forced: false }),
+        ),
+    };
+
+    let Some(command) = command else {
+        eprintln!("Invalid movement from {}", session_ctx);
+        return InMessageHandleResult::Break;
+    };
+
+    // Clients may only control the entities bound to their session
+    let entity = Entity::try_from_bits(entity).ok()
+        .filter(|entity| world.resource::<SessionRegistry>().get(*entity)
+            .is_some_and(|owner| owner.is_same(session_ctx)));
+    let Some(entity) = entity else {
+        eprintln!("Movement for an entity not owned by {}", session_ctx);
+        return InMessageHandleResult::Break;
+    };
+
+    let Some(mut controller) = world.get_mut::<MovementController>(entity) else {
+        eprintln!("Movement for an entity without movement from {}", session_ctx);
+        return InMessageHandleResult::Break;
+    };
+
+    if !controller.push_command(command) {
+        eprintln!("Too many movements from {}", session_ctx);
+        session_ctx.close(CloseReason::ProtocolError);
+    }
+
+    InMessageHandleResult::Break
+}
+
+fn to_direction(direction: Option<Vector2>) -> Option<UnitVector2<f32>> {
+    UnitVector2::try_new(direction?.into(), f32::EPSILON)
+}
+
+fn to_mode_command(mode: i32) -> Option<MovementCommand> {
+    let command = match MovementMode::try_from(mode).ok()? {
+        MovementMode::Standing => MovementCommand::Stand,
+        MovementMode::Crouching => MovementCommand::Crouch,
+        MovementMode::Crawling => MovementCommand::Crawl,
+        MovementMode::Swimming => MovementCommand::Swim,
+        MovementMode::Flying => MovementCommand::Fly,
+    };
+    Some(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::character::Character;
+    use crate::character::Race;
+    use crate::character::stat::CharacterStat;
+    use crate::character::status_effect::StatusEffectController;
+    use crate::core::session::Session;
+    use crate::physics::object::Transform;
+    use crate::player::PlayerBundle;
+    use crate::player::account::{Account, Privilege};
+    use crate::protocol::game::Move;
+    use crate::world::time::WorldTime;
+    use bevy_ecs::system::RunSystemOnce;
+    use bytes::Bytes;
+    use std::net::SocketAddr;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_handle_move() {
+        let mut world = World::default();
+        world.init_resource::<SessionRegistry>();
+        world.init_resource::<ChunkGrid>();
+        world.init_resource::<MovementViolations>();
+        world.init_resource::<MovementTuning>();
+        world.insert_resource(WorldTime { dt: time::Duration::from_millis(50), ..Default::default() });
+        SessionRegistry::register_hooks(&mut world);
+
+        let (close_tx, mut close_rx) = mpsc::channel(1);
+        let (retrieve_tx, _) = mpsc::channel(1);
+        let session_ctx = SessionContext::new(SocketAddr::from(([127, 0, 0, 1], 0)), close_tx, retrieve_tx);
+        let (server_message_tx, _) = mpsc::channel(1);
+        let server_ctx = Arc::new(ServerContext::new(server_message_tx));
+
+        let player_bundle = PlayerBundle::new(
+            Account { account_id: 7, privilege: Privilege::None },
+            Session::new(session_ctx.clone()),
+            Character { id: 1, name: "Tester".to_string(), race: Race::Human },
+            CharacterStat::default(),
+            StatusEffectController::default(),
+        );
+        let entity = world.spawn(player_bundle).id();
+
+        let protocol = GameClientProtocol {
+            protocol: Some(game_client_protocol::Protocol::Move(Move {
+                entity: entity.to_bits(),
+                direction: Some(Vector2 { x: 1.0, y: 0.0 }),
+                run: false,
+            }))
+        };
+        let message = (session_ctx, ProtocolCategory::Game, Bytes::from(protocol.encode_to_vec()));
+        handle_game_in_message(&message, &server_ctx, &mut world);
+
+        world.run_system_once(movement::validate).unwrap();
+        world.run_system_once(movement::update).unwrap();
+        assert!(world.get::<Transform>(entity).unwrap().position.x > 0.0);
+
+        // Flooding the commands between the ticks closes the session
+        for _ in 0..64 {
+            handle_game_in_message(&message, &server_ctx, &mut world);
+        }
+        assert_eq!(close_rx.try_recv().unwrap(), CloseReason::ProtocolError);
+    }
+}
@@ -1,2 +1,4 @@
 pub mod chunk;
+pub mod environment;
+pub mod interest;
 pub mod time;
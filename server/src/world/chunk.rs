@@ -0,0 +1,268 @@
+use bevy_ecs::prelude::*;
+use crate::physics::object::{KinematicBody, StaticBody, Transform, TriggerBody};
+use nalgebra::{Point2, Vector2};
+use std::collections::{HashMap, HashSet};
+
+pub const CHUNK_SIZE: f32 = 32.0;
+
+#[derive(Debug, Eq, PartialEq, Hash, Copy, Clone)]
+pub struct ChunkCoord {
+    pub x: i32,
+    pub y: i32,
+}
+
+impl ChunkCoord {
+    pub fn new(x: i32, y: i32) -> Self {
+        ChunkCoord { x, y }
+    }
+
+    pub fn from_position(position: &Point2<f32>) -> Self {
+        ChunkCoord {
+            x: (position.x / CHUNK_SIZE).floor() as i32,
+            y: (position.y / CHUNK_SIZE).floor() as i32,
+        }
+    }
+
+    /// Chunks crossed by the segment from `p` to `p + v`, in order.
+    pub fn traverse(p: &Point2<f32>, v: &Vector2<f32>) -> Vec<ChunkCoord> {
+        let mut coord = ChunkCoord::from_position(p);
+        let end = ChunkCoord::from_position(&(p + v));
+        let mut coords = vec![coord];
+
+        // Ratio along `v` to the next chunk boundary of an axis, and between two boundaries
+        let axis = |c: i32, p: f32, v: f32| {
+            if v == 0.0 {
+                return (0, f32::INFINITY, f32::INFINITY);
+            }
+            let step = if v > 0.0 { 1 } else { -1 };
+            let boundary = (c + step.max(0)) as f32 * CHUNK_SIZE;
+            (step, (boundary - p) / v, CHUNK_SIZE / v.abs())
+        };
+        let (step_x, mut t_x, delta_x) = axis(coord.x, p.x, v.x);
+        let (step_y, mut t_y, delta_y) = axis(coord.y, p.y, v.y);
+
+        while coord != end && t_x.min(t_y) <= 1.0 {
+            if t_x < t_y {
+                coord.x += step_x;
+                t_x += delta_x;
+            } else {
+                coord.y += step_y;
+                t_y += delta_y;
+            }
+            coords.push(coord);
+        }
+
+        coords
+    }
+
+    /// Chunks within `range` chunks of this chunk, including itself.
+    pub fn neighbours(self, range: i32) -> impl Iterator<Item = ChunkCoord> {
+        (-range..=range).flat_map(move |dx| {
+            (-range..=range).map(move |dy| ChunkCoord::new(self.x + dx, self.y + dy))
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct Chunk {
+    entities: HashSet<Entity>,
+}
+
+impl Chunk {
+    pub fn entities(&self) -> impl Iterator<Item = &Entity> {
+        self.entities.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+}
+
+/// Spatial index of the room. Entities with `Transform` are tracked by the chunk they are in.
+#[derive(Resource, Default)]
+pub struct ChunkGrid {
+    chunks: HashMap<ChunkCoord, Chunk>,
+    locations: HashMap<Entity, ChunkCoord>,
+    // Largest bounding radius of the bodies, never shrinking
+    max_extent: f32,
+}
+
+impl ChunkGrid {
+    pub fn load(&mut self, coord: ChunkCoord) -> &mut Chunk {
+        self.chunks.entry(coord).or_default()
+    }
+
+    /// Unloads the chunk, returning it with the entities left in it.
+    pub fn unload(&mut self, coord: ChunkCoord) -> Option<Chunk> {
+        let chunk = self.chunks.remove(&coord)?;
+        for entity in chunk.entities() {
+            self.locations.remove(entity);
+        }
+
+        Some(chunk)
+    }
+
+    pub fn unload_empty(&mut self) {
+        self.chunks.retain(|_, chunk| !chunk.is_empty());
+    }
+
+    pub fn is_loaded(&self, coord: ChunkCoord) -> bool {
+        self.chunks.contains_key(&coord)
+    }
+
+    pub fn chunk(&self, coord: ChunkCoord) -> Option<&Chunk> {
+        self.chunks.get(&coord)
+    }
+
+    pub fn location(&self, entity: Entity) -> Option<ChunkCoord> {
+        self.locations.get(&entity).copied()
+    }
+
+    /// Moves the entity to the chunk of `position`, loading the chunk if needed.
+    pub fn relocate(&mut self, entity: Entity, position: &Point2<f32>) {
+        let coord = ChunkCoord::from_position(position);
+        match self.locations.insert(entity, coord) {
+            Some(previous) if previous == coord => return,
+            Some(previous) => {
+                if let Some(chunk) = self.chunks.get_mut(&previous) {
+                    chunk.entities.remove(&entity);
+                }
+            }
+            None => {}
+        }
+
+        self.load(coord).entities.insert(entity);
+    }
+
+    pub fn remove(&mut self, entity: Entity) {
+        let Some(coord) = self.locations.remove(&entity) else {
+            return;
+        };
+
+        if let Some(chunk) = self.chunks.get_mut(&coord) {
+            chunk.entities.remove(&entity);
+        }
+    }
+
+    pub fn extend(&mut self, radius: f32) {
+        self.max_extent = self.max_extent.max(radius);
+    }
+
+    /// Bodies are indexed by their center only, so searches for bodies are widened by this.
+    pub fn max_extent(&self) -> f32 {
+        self.max_extent
+    }
+
+    /// Entities in the loaded chunks within `range` chunks of `coord`.
+    pub fn entities_near(&self, coord: ChunkCoord, range: i32) -> impl Iterator<Item = &Entity> {
+        coord.neighbours(range)
+            .filter_map(|coord| self.chunks.get(&coord))
+            .flat_map(|chunk| chunk.entities())
+    }
+
+    /// Entities in the chunks within `radius` of the segment from `p` to `p + v`.
+    pub fn entities_along(&self, p: &Point2<f32>, v: &Vector2<f32>, radius: f32) -> impl Iterator<Item = &Entity> {
+        let range = (radius / CHUNK_SIZE).ceil() as i32;
+        let coords: HashSet<ChunkCoord> = ChunkCoord::traverse(p, v).into_iter()
+            .flat_map(|coord| coord.neighbours(range))
+            .collect();
+
+        coords.into_iter()
+            .filter_map(|coord| self.chunks.get(&coord))
+            .flat_map(|chunk| chunk.entities())
+    }
+
+    /// Entities in the chunks overlapping the circle. Callers still need an exact distance check.
+    pub fn entities_around(&self, position: &Point2<f32>, radius: f32) -> impl Iterator<Item = &Entity> {
+        let range = (radius / CHUNK_SIZE).ceil() as i32;
+        self.entities_near(ChunkCoord::from_position(position), range)
+    }
+}
+
+pub fn update(
+    mut grid: ResMut<ChunkGrid>,
+    moved: Query<(Entity, &Transform), Changed<Transform>>,
+    mut removed: RemovedComponents<Transform>,
+    static_bodies: Query<&StaticBody, Added<StaticBody>>,
+    kinematic_bodies: Query<&KinematicBody, Added<KinematicBody>>,
+    trigger_bodies: Query<&TriggerBody, Added<TriggerBody>>,
+) {
+    let shapes = static_bodies.iter().map(|body| &body.shape)
+        .chain(kinematic_bodies.iter().map(|body| &body.shape))
+        .chain(trigger_bodies.iter().map(|body| &body.shape));
+    for shape in shapes {
+        grid.extend(shape.bounding_radius());
+    }
+
+    for entity in removed.read() {
+        grid.remove(entity);
+    }
+
+    moved.iter().for_each(|(entity, transform)| {
+        grid.relocate(entity, &transform.position);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_relocate() {
+        let mut grid = ChunkGrid::default();
+        let entity = Entity::from_raw(0);
+
+        grid.relocate(entity, &Point2::new(1.0, 1.0));
+        assert_eq!(grid.location(entity), Some(ChunkCoord::new(0, 0)));
+
+        grid.relocate(entity, &Point2::new(-1.0, CHUNK_SIZE + 1.0));
+        assert_eq!(grid.location(entity), Some(ChunkCoord::new(-1, 1)));
+        assert!(grid.chunk(ChunkCoord::new(0, 0)).unwrap().is_empty());
+
+        grid.remove(entity);
+        assert_eq!(grid.location(entity), None);
+        assert!(grid.chunk(ChunkCoord::new(-1, 1)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_chunk_neighbours() {
+        let mut grid = ChunkGrid::default();
+        let near = Entity::from_raw(0);
+        let far = Entity::from_raw(1);
+
+        grid.relocate(near, &Point2::new(CHUNK_SIZE + 1.0, 1.0));
+        grid.relocate(far, &Point2::new(CHUNK_SIZE * 3.0, 1.0));
+
+        let found: Vec<_> = grid.entities_near(ChunkCoord::new(0, 0), 1).collect();
+        assert_eq!(found, vec![&near]);
+
+        let found: Vec<_> = grid.entities_around(&Point2::new(0.0, 0.0), CHUNK_SIZE * 3.0).collect();
+        assert_eq!(found.len(), 2);
+    }
+
+    #[test]
+    fn test_chunk_traverse() {
+        let coords = ChunkCoord::traverse(&Point2::new(1.0, 1.0), &Vector2::new(CHUNK_SIZE * 2.0, CHUNK_SIZE));
+        assert_eq!(coords, vec![
+            ChunkCoord::new(0, 0),
+            ChunkCoord::new(1, 0),
+            ChunkCoord::new(1, 1),
+            ChunkCoord::new(2, 1),
+        ]);
+
+        let coords = ChunkCoord::traverse(&Point2::new(1.0, 1.0), &Vector2::new(-2.0, 0.0));
+        assert_eq!(coords, vec![ChunkCoord::new(0, 0), ChunkCoord::new(-1, 0)]);
+    }
+
+    #[test]
+    fn test_chunk_unload() {
+        let mut grid = ChunkGrid::default();
+        let entity = Entity::from_raw(0);
+
+        grid.relocate(entity, &Point2::new(1.0, 1.0));
+        let chunk = grid.unload(ChunkCoord::new(0, 0)).unwrap();
+        assert_eq!(chunk.entities().collect::<Vec<_>>(), vec![&entity]);
+        assert!(!grid.is_loaded(ChunkCoord::new(0, 0)));
+        assert_eq!(grid.location(entity), None);
+    }
+}
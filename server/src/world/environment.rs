@@ -0,0 +1,63 @@
+use bevy_ecs::prelude::*;
+use crate::physics::collision::dotcast;
+use crate::physics::object::{Transform, TriggerBody};
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Medium {
+    Water,
+    Air,
+}
+
+/// A region of water or open air, shaped by its `TriggerBody`.
+#[derive(Component)]
+pub struct Region {
+    pub medium: Medium,
+}
+
+/// Media the entity is in, updated from the regions containing its position.
+#[derive(Component, Default)]
+pub struct Environment {
+    in_water: bool,
+    in_air: bool,
+}
+
+impl Environment {
+    pub fn is_in_water(&self) -> bool {
+        self.in_water
+    }
+
+    pub fn can_swim(&self) -> bool {
+        self.in_water
+    }
+
+    /// Flying is only allowed in open air, not under water.
+    pub fn can_fly(&self) -> bool {
+        self.in_air && !self.in_water
+    }
+}
+
+pub fn update(
+    mut query: Query<(&Transform, &mut Environment), Changed<Transform>>,
+    // Regions are few and large, so they are checked without the chunk grid
+    regions: Query<(&Transform, &TriggerBody, &Region), Without<Environment>>,
+) {
+    query.iter_mut().for_each(|(transform, mut environment)| {
+        let mut in_water = false;
+        let mut in_air = false;
+        for (region, body, Region { medium }) in &regions {
+            if !dotcast(transform.position, region.position, &body.shape) {
+                continue;
+            }
+
+            match medium {
+                Medium::Water => in_water = true,
+                Medium::Air => in_air = true,
+            }
+        }
+
+        if environment.in_water != in_water || environment.in_air != in_air {
+            environment.in_water = in_water;
+            environment.in_air = in_air;
+        }
+    });
+}
@@ -0,0 +1,117 @@
+use bevy_ecs::prelude::*;
+use crate::character::vision::Vision;
+use crate::core::room_resource::SessionRegistry;
+use crate::physics::object::Transform;
+use crate::protocol::*;
+use crate::protocol::game::*;
+use crate::world::chunk::ChunkGrid;
+use std::collections::HashSet;
+
+/// Area of interest of an observer. Only relevant entities are synced to the observer's session.
+/// Observers with `Vision` only find the entities in their sight relevant.
+#[derive(Component)]
+pub struct Interest {
+    pub radius: f32,
+    relevant: HashSet<Entity>,
+    entered: Vec<Entity>,
+}
+
+impl Interest {
+    pub fn new(radius: f32) -> Self {
+        Interest {
+            radius,
+            relevant: HashSet::new(),
+            entered: Vec::new(),
+        }
+    }
+
+    pub fn is_relevant(&self, entity: Entity) -> bool {
+        self.relevant.contains(&entity)
+    }
+
+    pub fn relevant(&self) -> impl Iterator<Item = &Entity> {
+        self.relevant.iter()
+    }
+
+    /// Entities that became relevant on the last update.
+    pub fn entered(&self) -> &[Entity] {
+        &self.entered
+    }
+}
+
+impl Default for Interest {
+    fn default() -> Self {
+        Interest::new(64.0)
+    }
+}
+
+#[derive(Event)]
+pub struct ViewEntered {
+    pub observer: Entity,
+    pub target: Entity,
+}
+
+#[derive(Event)]
+pub struct ViewLeft {
+    pub observer: Entity,
+    pub target: Entity,
+}
+
+pub fn update(
+    grid: Res<ChunkGrid>,
+    mut observers: Query<(Entity, &Transform, &mut Interest, Option<&Vision>)>,
+    targets: Query<(Entity, &Transform)>,
+    sessions: Res<SessionRegistry>,
+    mut entered_events: EventWriter<ViewEntered>,
+    mut left_events: EventWriter<ViewLeft>,
+) {
+    observers.iter_mut().for_each(|(observer, observer_transform, mut interest, vision)| {
+        let radius = interest.radius;
+        let relevant: HashSet<Entity> = grid
+            .entities_around(&observer_transform.position, radius)
+            .filter(|target| match vision {
+                Some(vision) => **target == observer || vision.can_see(**target),
+                None => true,
+            })
+            .filter(|target| match targets.get(**target) {
+                Ok((_, transform)) => {
+                    nalgebra::distance(&observer_transform.position, &transform.position) <= radius
+                }
+                Err(_) => false,
+            })
+            .copied()
+            .collect();
+
+        let entered: Vec<Entity> = relevant.difference(&interest.relevant).copied().collect();
+        let left: Vec<Entity> = interest.relevant.difference(&relevant).copied().collect();
+
+        for target in &entered {
+            entered_events.send(ViewEntered { observer, target: *target });
+        }
+        for target in &left {
+            left_events.send(ViewLeft { observer, target: *target });
+        }
+
+        if !entered.is_empty() {
+            send_view(&sessions, observer, game_server_protocol::Protocol::ViewEnter(ViewEnter {
+                entities: entered.iter().map(|entity| entity.to_bits()).collect(),
+            }));
+        }
+        if !left.is_empty() {
+            send_view(&sessions, observer, game_server_protocol::Protocol::ViewLeave(ViewLeave {
+                entities: left.iter().map(|entity| entity.to_bits()).collect(),
+            }));
+        }
+
+        interest.relevant = relevant;
+        interest.entered = entered;
+    });
+}
+
+fn send_view(sessions: &SessionRegistry, observer: Entity, protocol: game_server_protocol::Protocol) {
+    let protocol = GameServerProtocol { protocol: Some(protocol) };
+    match serialize_protocol(ProtocolCategory::Game, &protocol) {
+        Ok(buf) => sessions.send(observer, buf),
+        Err(e) => eprintln!("Failed to serialize view protocol: {}", e),
+    }
+}